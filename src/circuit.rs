@@ -0,0 +1,238 @@
+// circuit.rs - Per-host circuit breaker for failing upstreams
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+/// A fetch refused because its host's circuit is open
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Upstream host {host} is failing, fetches resume in {retry_in:?}")]
+pub struct CircuitOpen {
+    pub host: String,
+    pub retry_in: Duration,
+}
+
+/// Failure history of one host
+#[derive(Debug)]
+struct HostCircuit {
+    /// Consecutive failures since `first_failure`
+    failures: u32,
+    first_failure: Instant,
+    /// Set while fetches fail fast
+    opened_at: Option<Instant>,
+    /// Set while the one fetch allowed after the cooldown is running
+    probe_started: Option<Instant>,
+}
+
+/// Fails fetches fast for hosts that keep failing. After `threshold`
+/// consecutive failures within `window` the circuit opens for `cooldown`,
+/// then a single probe fetch decides whether it closes or opens again
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    hosts: DashMap<String, HostCircuit>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(5, Duration::from_secs(30), Duration::from_secs(30))
+    }
+}
+
+impl CircuitBreaker {
+    /// A `threshold` of 0 disables the breaker
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            window,
+            cooldown,
+            hosts: DashMap::new(),
+        }
+    }
+
+    /// Check whether a fetch from `host` may go ahead
+    pub fn check(&self, host: &str) -> Result<(), CircuitOpen> {
+        let Some(mut circuit) = self.hosts.get_mut(&host.to_ascii_lowercase()) else {
+            return Ok(());
+        };
+        let Some(opened_at) = circuit.opened_at else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let reopens = opened_at + self.cooldown;
+        // A probe that never reported back (its request was dropped) is
+        // replaced after another cooldown
+        let probing = circuit
+            .probe_started
+            .is_some_and(|started| now.duration_since(started) < self.cooldown);
+        if now < reopens || probing {
+            let retry_in = match circuit.probe_started {
+                Some(started) if probing => (started + self.cooldown).saturating_duration_since(now),
+                _ => reopens.saturating_duration_since(now),
+            };
+            return Err(CircuitOpen {
+                host: host.to_string(),
+                retry_in,
+            });
+        }
+        circuit.probe_started = Some(now);
+        Ok(())
+    }
+
+    /// Record a fetch from `host` that got an answer, closing its circuit
+    pub fn record_success(&self, host: &str) {
+        self.hosts.remove(&host.to_ascii_lowercase());
+    }
+
+    /// Record a failed fetch from `host`, opening its circuit when the
+    /// threshold is reached or a probe failed
+    pub fn record_failure(&self, host: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut circuit = self.hosts.entry(host.to_ascii_lowercase()).or_insert(HostCircuit {
+            failures: 0,
+            first_failure: now,
+            opened_at: None,
+            probe_started: None,
+        });
+
+        if circuit.probe_started.take().is_some() {
+            circuit.opened_at = Some(now);
+            return;
+        }
+        if circuit.opened_at.is_some() {
+            return;
+        }
+        if now.duration_since(circuit.first_failure) > self.window {
+            circuit.failures = 0;
+            circuit.first_failure = now;
+        }
+        circuit.failures += 1;
+        if circuit.failures >= self.threshold {
+            circuit.opened_at = Some(now);
+        }
+    }
+
+    /// Hosts whose circuit is open, including those being probed
+    pub fn open_hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self
+            .hosts
+            .iter()
+            .filter(|circuit| circuit.opened_at.is_some())
+            .map(|circuit| circuit.key().clone())
+            .collect();
+        hosts.sort();
+        hosts
+    }
+
+    /// Number of hosts with failures on record
+    pub fn host_count(&self) -> usize {
+        self.hosts.len()
+    }
+
+    /// Forget closed circuits whose failures are older than the window
+    pub fn remove_expired(&self) {
+        let now = Instant::now();
+        self.hosts.retain(|_, circuit| {
+            circuit.opened_at.is_some() || now.duration_since(circuit.first_failure) <= self.window
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(3, Duration::from_secs(10), Duration::from_secs(30))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_opens_after_consecutive_failures() {
+        let breaker = breaker();
+        for _ in 0..2 {
+            breaker.record_failure("cdn.example.com");
+        }
+        assert!(breaker.check("cdn.example.com").is_ok());
+
+        // A success in between starts the count over
+        breaker.record_success("cdn.example.com");
+        for _ in 0..2 {
+            breaker.record_failure("cdn.example.com");
+        }
+        assert!(breaker.check("cdn.example.com").is_ok());
+
+        breaker.record_failure("CDN.example.com");
+        let open = breaker.check("cdn.example.com").unwrap_err();
+        assert_eq!(open.retry_in, Duration::from_secs(30));
+        assert_eq!(breaker.open_hosts(), vec!["cdn.example.com"]);
+        assert!(breaker.check("other.example.com").is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failures_outside_the_window_are_forgotten() {
+        let breaker = breaker();
+        breaker.record_failure("cdn.example.com");
+        breaker.record_failure("cdn.example.com");
+        tokio::time::advance(Duration::from_secs(11)).await;
+        breaker.record_failure("cdn.example.com");
+        assert!(breaker.check("cdn.example.com").is_ok());
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        breaker.remove_expired();
+        assert_eq!(breaker.host_count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_probe_after_cooldown() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            breaker.record_failure("cdn.example.com");
+        }
+        tokio::time::advance(Duration::from_secs(30)).await;
+
+        // One probe goes through while others keep failing fast
+        assert!(breaker.check("cdn.example.com").is_ok());
+        assert!(breaker.check("cdn.example.com").is_err());
+
+        // A failed probe opens the circuit for another cooldown
+        breaker.record_failure("cdn.example.com");
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(breaker.check("cdn.example.com").is_err());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(breaker.check("cdn.example.com").is_ok());
+
+        // A successful one closes it
+        breaker.record_success("cdn.example.com");
+        assert!(breaker.check("cdn.example.com").is_ok());
+        assert!(breaker.open_hosts().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_abandoned_probe_is_replaced() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            breaker.record_failure("cdn.example.com");
+        }
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.check("cdn.example.com").is_ok());
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.check("cdn.example.com").is_ok());
+    }
+
+    #[test]
+    fn test_zero_threshold_disables() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(10), Duration::from_secs(30));
+        for _ in 0..100 {
+            breaker.record_failure("cdn.example.com");
+        }
+        assert!(breaker.check("cdn.example.com").is_ok());
+        assert_eq!(breaker.host_count(), 0);
+    }
+}
@@ -1,7 +1,12 @@
 // compress.rs - Image compression module
 
+use bytes::Bytes;
 use image::{DynamicImage, GenericImageView, ImageFormat, ImageReader};
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "avif")]
 use ravif::{Encoder, AlphaColorMode, BitDepth};
@@ -11,20 +16,217 @@ use rgb::RGBA8;
 use crate::logger::Logger;
 
 /// Configuration constants for compression
+#[derive(Debug, Clone)]
 pub struct Config {
     pub max_width: u32,
+    /// Maximum output height in pixels (unbounded by default)
+    pub max_height: u32,
+    /// Upper bound for `max_width` once scaled by the device pixel ratio
+    pub max_dpr_width: u32,
     pub max_jpeg_height: u32,
     pub max_avif_height: u32,
+    /// Height above which WebP can't be used
+    pub max_webp_height: u32,
+    /// Grayscale quality is clamped to this `(min, max)` range;
+    /// `(0, 100)` disables the clamp
     pub grayscale_quality_range: (u8, u8),
+    /// Chroma subsampling for color JPEG output
+    pub jpeg_subsampling: ChromaSubsampling,
+    /// Images with at most this many unique colors are tried as lossless WebP
+    pub lossless_max_colors: usize,
+    /// PNG sources up to this size are tried as lossless WebP
+    pub lossless_max_png_size: u64,
+    /// oxipng preset level (0-6) for PNG outputs
+    #[cfg_attr(not(feature = "oxipng"), allow(dead_code))]
+    pub png_optimization_level: u8,
+    /// Maximum time spent re-optimizing a PNG output
+    #[cfg_attr(not(feature = "oxipng"), allow(dead_code))]
+    pub png_optimization_timeout: Duration,
+    /// Lowest acceptable palette quantization quality (0-100, PSNR based);
+    /// PNGs that would fall below it keep their full colors
+    #[cfg_attr(not(feature = "quantize"), allow(dead_code))]
+    pub png_quantize_min_quality: u8,
+    /// SVGs declaring a larger width or height are refused
+    #[cfg_attr(not(feature = "svg"), allow(dead_code))]
+    pub svg_max_dimension: f32,
+    /// Number of gray levels kept when dithering grayscale output
+    pub dither_levels: u8,
+    /// Compute a BlurHash placeholder for compressed images
+    pub blurhash_enabled: bool,
+    /// Unsharp-mask downscaled images unless the request says otherwise
+    pub sharpen_enabled: bool,
+    /// Blur radius of the unsharp mask
+    pub sharpen_sigma: f32,
+    /// Minimum brightness difference the unsharp mask acts on
+    pub sharpen_threshold: i32,
+    /// Longest side of LQIP placeholders
+    pub lqip_max_dimension: u32,
+    /// JPEG quality used for LQIP placeholders
+    pub lqip_quality: u8,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             max_width: 800,
+            max_height: u32::MAX,
+            max_dpr_width: 1200,
             max_jpeg_height: 32767,
             max_avif_height: 16383,
+            max_webp_height: 16383,
             grayscale_quality_range: (15, 50),
+            jpeg_subsampling: ChromaSubsampling::default(),
+            lossless_max_colors: 256,
+            lossless_max_png_size: 200 * 1024,
+            png_optimization_level: 2,
+            png_optimization_timeout: Duration::from_millis(500),
+            png_quantize_min_quality: 50,
+            svg_max_dimension: 16384.0,
+            dither_levels: 16,
+            blurhash_enabled: true,
+            sharpen_enabled: false,
+            sharpen_sigma: 0.5,
+            sharpen_threshold: 2,
+            lqip_max_dimension: 32,
+            lqip_quality: 20,
+        }
+    }
+}
+
+impl Config {
+    /// Check settings that would otherwise misbehave or panic mid-request
+    pub fn validate(&self) -> Result<(), String> {
+        let (min, max) = self.grayscale_quality_range;
+        if min > max || max > 100 {
+            return Err(format!(
+                "Invalid grayscale quality range {}-{}: expected min <= max <= 100",
+                min, max
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Per-request compression options
+#[derive(Debug, Clone)]
+pub struct CompressParams {
+    pub use_avif: bool,
+    /// The client takes WebP: lossy WebP is encoded when AVIF isn't used,
+    /// and flat graphics may come out as lossless WebP
+    pub use_webp: bool,
+    /// Explicitly requested output format; `None` picks one automatically
+    pub format: Option<OutputFormat>,
+    pub grayscale: bool,
+    /// Dither grayscale output instead of letting low quality band it
+    pub dither: bool,
+    /// Device pixel ratio of the client display (1.0-3.0)
+    pub dpr: f32,
+    /// Reduce animated GIFs to their first frame
+    pub still: bool,
+    /// Explicit maximum output width, overriding `max_width` and `dpr`
+    pub width: Option<u32>,
+    /// Explicit maximum output height, overriding `max_height`
+    pub height: Option<u32>,
+    /// Encode both AVIF and JPEG and keep the smaller one
+    pub dual_encode: bool,
+    /// Return a tiny low quality placeholder instead of the full image
+    pub lqip: bool,
+    /// Brightness adjustment in percent (-100 to 100, 0 = unchanged)
+    pub brightness: i32,
+    /// Contrast adjustment in percent (-100 to 100, 0 = unchanged)
+    pub contrast: i32,
+    /// Invert colors (after grayscale conversion) for dark-mode reading
+    pub invert: bool,
+    /// Sharpen after downscaling; `None` uses `Config::sharpen_enabled`
+    pub sharpen: Option<bool>,
+    pub rotate: Rotation,
+    pub flip: Option<Flip>,
+    pub quality: u8,
+    pub original_size: u64,
+}
+
+/// Clockwise rotation applied to the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    None,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+/// Mirror applied to the output after rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+/// JPEG chroma subsampling. 4:4:4 keeps colored text sharp, 4:2:0 is
+/// smaller for photos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaSubsampling {
+    S420,
+    S422,
+    #[default]
+    S444,
+}
+
+impl ChromaSubsampling {
+    /// Short name used in logs and configuration (`420`, `422`, `444`)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChromaSubsampling::S420 => "420",
+            ChromaSubsampling::S422 => "422",
+            ChromaSubsampling::S444 => "444",
+        }
+    }
+
+    fn sampling_factor(&self) -> jpeg_encoder::SamplingFactor {
+        match self {
+            ChromaSubsampling::S420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+            ChromaSubsampling::S422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+            ChromaSubsampling::S444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+        }
+    }
+}
+
+impl std::str::FromStr for ChromaSubsampling {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().replace(':', "").as_str() {
+            "420" => Ok(ChromaSubsampling::S420),
+            "422" => Ok(ChromaSubsampling::S422),
+            "444" => Ok(ChromaSubsampling::S444),
+            _ => Err(format!("Unknown chroma subsampling: {}", value)),
+        }
+    }
+}
+
+/// Output formats the compressor can produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jpeg,
+    Avif,
+    WebP,
+    /// Only produced when explicitly requested
+    Png,
+    #[cfg(feature = "jxl")]
+    Jxl,
+}
+
+impl OutputFormat {
+    /// Short name used in `image/<name>` content types and logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Avif => "avif",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Png => "png",
+            #[cfg(feature = "jxl")]
+            OutputFormat::Jxl => "jxl",
         }
     }
 }
@@ -32,9 +234,25 @@ impl Default for Config {
 /// Result of compression operation
 #[derive(Debug)]
 pub struct CompressionResult {
-    pub data: Vec<u8>,
-    pub format: String,
+    pub data: Bytes,
+    /// Encoded output format, or `None` when `data` is the original image
+    pub format: Option<OutputFormat>,
     pub bytes_saved: i64,
+    pub decode_ms: u64,
+    pub resize_ms: u64,
+    pub encode_ms: u64,
+    pub original_width: u32,
+    pub original_height: u32,
+    pub output_width: u32,
+    pub output_height: u32,
+    /// Set when the original was passed through instead of being compressed
+    pub bypass_reason: Option<&'static str>,
+    /// BlurHash placeholder of the output image
+    pub blurhash: Option<String>,
+    /// Set when an animated source was reduced to its first frame
+    pub animation_dropped: bool,
+    /// Requested format that couldn't be used for the output dimensions
+    pub format_fallback: Option<OutputFormat>,
 }
 
 /// Error types for compression
@@ -42,70 +260,603 @@ pub struct CompressionResult {
 pub enum CompressionError {
     #[error("Image processing error: {0}")]
     ImageError(String),
+    #[error("Image decode error: {0}")]
+    Decode(String),
+    #[error("Compression cancelled")]
+    Cancelled,
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
+    #[cfg(feature = "svg")]
+    #[error("Invalid SVG: {0}")]
+    InvalidSvg(String),
 }
 
-/// Calculate new dimensions maintaining aspect ratio
+/// Calculate new dimensions maintaining aspect ratio, never upscaling
 fn calculate_dimensions(
     width: u32,
     height: u32,
     max_width: u32,
+    max_height: u32,
 ) -> (u32, u32) {
-    if width <= max_width {
+    if width <= max_width && height <= max_height {
         return (width, height);
     }
 
-    let ratio = max_width as f32 / width as f32;
+    let ratio = (max_width as f32 / width as f32).min(max_height as f32 / height as f32);
+    (
+        ((width as f32 * ratio).round() as u32).max(1),
+        ((height as f32 * ratio).round() as u32).max(1),
+    )
+}
+
+/// Scale `max_width` by the device pixel ratio, capped at `max_dpr_width`
+fn effective_max_width(config: &Config, dpr: f32) -> u32 {
+    if dpr <= 1.0 {
+        return config.max_width;
+    }
+
+    let scaled = (config.max_width as f32 * dpr).round() as u32;
+    scaled.min(config.max_dpr_width).max(config.max_width)
+}
+
+/// Maximum output `(width, height)` for a request
+fn size_limits(params: &CompressParams, config: &Config) -> (u32, u32) {
+    if params.lqip {
+        // Placeholders ignore every sizing parameter
+        return (config.lqip_max_dimension, config.lqip_max_dimension);
+    }
+
     (
-        (width as f32 * ratio).round() as u32,
-        (height as f32 * ratio).round() as u32,
+        params.width.unwrap_or_else(|| effective_max_width(config, params.dpr)),
+        params.height.unwrap_or(config.max_height),
     )
 }
 
-/// Select the best output format based on client request and image properties
+/// Dimensions `compress` will produce for a `width` x `height` source
+pub fn output_dimensions(width: u32, height: u32, params: &CompressParams, config: &Config) -> (u32, u32) {
+    let (width, height) = match params.rotate {
+        Rotation::Rotate90 | Rotation::Rotate270 => (height, width),
+        _ => (width, height),
+    };
+    let (max_width, max_height) = size_limits(params, config);
+    calculate_dimensions(width, height, max_width, max_height)
+}
+
+/// Read image dimensions from the header without decoding pixel data
+pub fn probe_dimensions(image_data: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Check whether PNG data is animated (APNG has an acTL chunk before the first IDAT)
+fn is_animated_png(data: &[u8]) -> bool {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    if !data.starts_with(SIGNATURE) {
+        return false;
+    }
+
+    let mut offset = SIGNATURE.len();
+    while offset + 8 <= data.len() {
+        let length = u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ]) as usize;
+
+        match &data[offset + 4..offset + 8] {
+            b"acTL" => return true,
+            b"IDAT" => return false,
+            _ => {}
+        }
+
+        // Chunk = length + type + data + CRC
+        offset = offset.saturating_add(12).saturating_add(length);
+    }
+
+    false
+}
+
+/// Skip a chain of GIF data sub-blocks, returning the offset past the terminator
+fn skip_gif_sub_blocks(data: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *data.get(offset)? as usize;
+        offset += 1 + length;
+        if length == 0 {
+            return Some(offset);
+        }
+    }
+}
+
+/// Count GIF frames up to `limit` by walking the block structure, without
+/// decompressing any pixel data. Returns None for malformed data.
+fn count_gif_frames(data: &[u8], limit: usize) -> Option<usize> {
+    if !data.starts_with(b"GIF87a") && !data.starts_with(b"GIF89a") {
+        return None;
+    }
+
+    // Header + logical screen descriptor, then the optional global color table
+    let color_table_size = |flags: u8| if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 };
+    let mut offset = 13 + color_table_size(*data.get(10)?);
+    let mut frames = 0;
+
+    while frames < limit {
+        match *data.get(offset)? {
+            // Extension: introducer + label + sub-blocks
+            0x21 => offset = skip_gif_sub_blocks(data, offset + 2)?,
+            // Image descriptor + local color table + LZW code size + sub-blocks
+            0x2C => {
+                frames += 1;
+                offset += 10 + color_table_size(*data.get(offset + 9)?);
+                offset = skip_gif_sub_blocks(data, offset + 1)?;
+            }
+            // Trailer
+            0x3B => break,
+            _ => return None,
+        }
+    }
+
+    Some(frames)
+}
+
+/// Check whether a GIF has more than one frame
+fn is_animated_gif(data: &[u8]) -> bool {
+    count_gif_frames(data, 2).is_some_and(|frames| frames > 1)
+}
+
+/// Check whether the data looks like an SVG document
+#[cfg(feature = "svg")]
+fn is_svg(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    (head.starts_with("<svg") || head.starts_with("<?xml") || head.starts_with("<!"))
+        && head.contains("<svg")
+}
+
+/// Shared font database for SVG text, loaded once on first use
+#[cfg(feature = "svg")]
+fn svg_font_db() -> std::sync::Arc<resvg::usvg::fontdb::Database> {
+    static FONT_DB: std::sync::OnceLock<std::sync::Arc<resvg::usvg::fontdb::Database>> =
+        std::sync::OnceLock::new();
+
+    FONT_DB
+        .get_or_init(|| {
+            let mut db = resvg::usvg::fontdb::Database::new();
+            db.load_system_fonts();
+            std::sync::Arc::new(db)
+        })
+        .clone()
+}
+
+/// Rasterize an SVG document, scaled down to fit the configured maximum size
+#[cfg(feature = "svg")]
+fn rasterize_svg(data: &[u8], config: &Config) -> Result<DynamicImage, CompressionError> {
+    use resvg::{tiny_skia, usvg};
+
+    let options = usvg::Options {
+        // Never touch the filesystem or network for referenced resources;
+        // only inline data: URIs are rendered
+        resources_dir: None,
+        image_href_resolver: usvg::ImageHrefResolver {
+            resolve_data: usvg::ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|_, _| None),
+        },
+        fontdb: svg_font_db(),
+        ..usvg::Options::default()
+    };
+
+    let tree = usvg::Tree::from_data(data, &options)
+        .map_err(|e| CompressionError::InvalidSvg(e.to_string()))?;
+
+    let size = tree.size();
+    if size.width() > config.svg_max_dimension || size.height() > config.svg_max_dimension {
+        return Err(CompressionError::InvalidSvg(format!(
+            "SVG canvas too large: {}x{}",
+            size.width(),
+            size.height()
+        )));
+    }
+
+    let (width, height) = calculate_dimensions(
+        size.width().ceil() as u32,
+        size.height().ceil() as u32,
+        config.max_width,
+        config.max_height,
+    );
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| CompressionError::InvalidSvg("Invalid SVG canvas size".to_string()))?;
+
+    let transform = tiny_skia::Transform::from_scale(
+        width as f32 / size.width(),
+        height as f32 / size.height(),
+    );
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia stores premultiplied alpha
+    let mut rgba = image::RgbaImage::new(width, height);
+    for (dst, src) in rgba.pixels_mut().zip(pixmap.pixels()) {
+        let color = src.demultiply();
+        *dst = image::Rgba([color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+/// Check whether the data is a HEIC/HEIF container (ISO BMFF `ftyp` box with a HEIF brand)
+fn is_heif(data: &[u8]) -> bool {
+    const BRANDS: [&[u8]; 7] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1"];
+
+    data.len() >= 12 && &data[4..8] == b"ftyp" && BRANDS.contains(&&data[8..12])
+}
+
+/// Decode a HEIC/HEIF image via libheif
+#[cfg(feature = "heif")]
+fn decode_heif(data: &[u8]) -> Result<DynamicImage, CompressionError> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let to_error = |e: libheif_rs::HeifError| CompressionError::ImageError(e.to_string());
+
+    let lib_heif = LibHeif::new();
+    let context = HeifContext::read_from_bytes(data).map_err(to_error)?;
+    let handle = context.primary_image_handle().map_err(to_error)?;
+    let image = lib_heif
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(to_error)?;
+
+    let plane = image.planes().interleaved.ok_or_else(|| {
+        CompressionError::ImageError("HEIF image has no interleaved plane".to_string())
+    })?;
+
+    // Rows may be padded beyond width * 4 bytes
+    let row_bytes = plane.width as usize * 4;
+    let mut pixels = Vec::with_capacity(row_bytes * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_bytes]);
+    }
+
+    image::RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| CompressionError::ImageError("Truncated HEIF pixel data".to_string()))
+}
+
+/// Pick the JPEG DCT scale denominator (1, 2, 4 or 8) that still leaves
+/// at least `target_width` pixels for the final resize
+fn jpeg_scale_denominator(width: u32, target_width: u32) -> u32 {
+    [8, 4, 2]
+        .into_iter()
+        .find(|&d| width.div_ceil(d) >= target_width)
+        .unwrap_or(1)
+}
+
+/// Decode a JPEG at 1/2, 1/4 or 1/8 scale when the output is much narrower
+/// than the source. Returns None when a full decode is needed instead.
+/// With `quarter_turn` the output width comes from the source height.
+fn decode_jpeg_scaled(image_data: &[u8], target_width: u32, quarter_turn: bool) -> Option<DynamicImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(image_data));
+    decoder.read_info().ok()?;
+    let info = decoder.info()?;
+    let (width, height) = (info.width as u32, info.height as u32);
+
+    let output_side = if quarter_turn { height } else { width };
+    let denominator = jpeg_scale_denominator(output_side, target_width);
+    if denominator == 1 {
+        return None;
+    }
+
+    let (scaled_width, scaled_height) = decoder
+        .scale(width.div_ceil(denominator) as u16, height.div_ceil(denominator) as u16)
+        .ok()?;
+    let pixels = decoder.decode().ok()?;
+    let (scaled_width, scaled_height) = (scaled_width as u32, scaled_height as u32);
+
+    // CMYK and 16-bit sources go through the regular decoder
+    match decoder.info()?.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => image::GrayImage::from_raw(scaled_width, scaled_height, pixels)
+            .map(DynamicImage::ImageLuma8),
+        jpeg_decoder::PixelFormat::RGB24 => image::RgbImage::from_raw(scaled_width, scaled_height, pixels)
+            .map(DynamicImage::ImageRgb8),
+        _ => None,
+    }
+}
+
+/// Decode the source image, rasterizing SVG documents when supported.
+/// `quarter_turn` signals that the image will be rotated by 90 or 270 degrees.
+fn decode_source(
+    image_data: &[u8],
+    config: &Config,
+    quarter_turn: bool,
+) -> Result<(DynamicImage, Option<ImageFormat>), CompressionError> {
+    #[cfg(feature = "svg")]
+    if is_svg(image_data) {
+        return Ok((rasterize_svg(image_data, config)?, None));
+    }
+
+    #[cfg(feature = "heif")]
+    if is_heif(image_data) {
+        return Ok((decode_heif(image_data)?, None));
+    }
+
+    let reader = ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .map_err(|e| CompressionError::Decode(e.to_string()))?;
+    let source_format = reader.format();
+
+    // Large JPEGs only need enough pixels for the final resize
+    if source_format == Some(ImageFormat::Jpeg) {
+        if let Some(img) = decode_jpeg_scaled(image_data, config.max_width, quarter_turn) {
+            return Ok((img, source_format));
+        }
+    }
+
+    let img = reader
+        .decode()
+        .map_err(|e| CompressionError::Decode(e.to_string()))?;
+
+    Ok((img, source_format))
+}
+
+/// Build a result that returns the original data untouched
+fn passthrough_result(image_data: &Bytes, reason: &'static str) -> CompressionResult {
+    let (width, height) = probe_dimensions(image_data).unwrap_or((0, 0));
+
+    CompressionResult {
+        data: image_data.clone(),
+        format: None,
+        bytes_saved: 0,
+        decode_ms: 0,
+        resize_ms: 0,
+        encode_ms: 0,
+        original_width: width,
+        original_height: height,
+        output_width: width,
+        output_height: height,
+        bypass_reason: Some(reason),
+        blurhash: None,
+        animation_dropped: false,
+        format_fallback: None,
+    }
+}
+
+/// Clamp the requested quality into the grayscale `(min, max)` range
+fn grayscale_quality(quality: u8, (min, max): (u8, u8)) -> u8 {
+    quality.clamp(min, max)
+}
+
+/// Select the best output format based on client request and image properties.
+/// An explicit `requested` format is used as long as it can handle the height.
 fn select_format(
     use_avif: bool,
+    use_webp: bool,
+    requested: Option<OutputFormat>,
     calculated_height: u32,
     config: &Config,
-) -> ImageFormat {
-    // If client requested JPEG (use_avif = false), always use JPEG
+) -> OutputFormat {
+    match requested {
+        // JPEG XL has no practical dimension limits
+        #[cfg(feature = "jxl")]
+        Some(OutputFormat::Jxl) => return OutputFormat::Jxl,
+        Some(OutputFormat::Avif) if calculated_height <= config.max_avif_height => return OutputFormat::Avif,
+        Some(OutputFormat::WebP) if calculated_height <= config.max_webp_height => return OutputFormat::WebP,
+        Some(OutputFormat::Jpeg) if calculated_height > config.max_jpeg_height => return OutputFormat::Png,
+        Some(OutputFormat::Png) => return OutputFormat::Png,
+        // Everything else falls back to JPEG
+        Some(_) => return OutputFormat::Jpeg,
+        None => {}
+    }
+
+    // Clients that take WebP but not AVIF get lossy WebP, others JPEG
     if !use_avif {
-        return ImageFormat::Jpeg;
+        if use_webp && calculated_height <= config.max_webp_height {
+            return OutputFormat::WebP;
+        }
+        return OutputFormat::Jpeg;
     }
 
     // Client requested WebP, use AVIF as the optimized format
     // But fall back to JPEG if height exceeds limits
     if calculated_height > config.max_jpeg_height {
-        return ImageFormat::Jpeg;
+        return OutputFormat::Jpeg;
     }
 
     if calculated_height > config.max_avif_height {
-        return ImageFormat::Jpeg;
+        return OutputFormat::Jpeg;
+    }
+
+    OutputFormat::Avif
+}
+
+/// Resize to exact dimensions with a Lanczos3 filter
+fn resize_image(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    #[cfg(feature = "fast-resize")]
+    if let Some(resized) = fast_resize(img, width, height) {
+        return resized;
+    }
+
+    img.resize_exact(width, height, image::imageops::FilterType::Lanczos3)
+}
+
+/// SIMD Lanczos3 resize via fast_image_resize. Returns None for pixel
+/// formats it doesn't support, so the caller can fall back to `image`.
+#[cfg(feature = "fast-resize")]
+fn fast_resize(img: &DynamicImage, width: u32, height: u32) -> Option<DynamicImage> {
+    use fast_image_resize::{FilterType, ResizeAlg, ResizeOptions, Resizer};
+
+    let mut resized = DynamicImage::new(width, height, img.color());
+    let options = ResizeOptions::new().resize_alg(ResizeAlg::Convolution(FilterType::Lanczos3));
+    Resizer::new().resize(img, &mut resized, &options).ok()?;
+
+    Some(resized)
+}
+
+/// Composite an image with alpha onto a white background
+fn flatten_alpha(img: &DynamicImage) -> DynamicImage {
+    let blend = |channel: u8, alpha: u8| {
+        ((channel as u16 * alpha as u16 + 255 * (255 - alpha as u16)) / 255) as u8
+    };
+
+    if !img.color().has_color() {
+        let luma_alpha = img.to_luma_alpha8();
+        let flattened = image::GrayImage::from_fn(img.width(), img.height(), |x, y| {
+            let image::LumaA([l, a]) = *luma_alpha.get_pixel(x, y);
+            image::Luma([blend(l, a)])
+        });
+        return DynamicImage::ImageLuma8(flattened);
+    }
+
+    let rgba = img.to_rgba8();
+    let flattened = image::RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let image::Rgba([r, g, b, a]) = *rgba.get_pixel(x, y);
+        image::Rgb([blend(r, a), blend(g, a), blend(b, a)])
+    });
+    DynamicImage::ImageRgb8(flattened)
+}
+
+/// Evenly spaced gray levels used as the palette for dithering
+struct GrayLevels(u8);
+
+impl image::imageops::ColorMap for GrayLevels {
+    type Color = image::Luma<u8>;
+
+    fn index_of(&self, color: &Self::Color) -> usize {
+        let steps = self.0.max(2) as u32 - 1;
+        ((color.0[0] as u32 * steps + 127) / 255) as usize
+    }
+
+    fn map_color(&self, color: &mut Self::Color) {
+        let steps = self.0.max(2) as u32 - 1;
+        color.0[0] = (self.index_of(color) as u32 * 255 / steps) as u8;
+    }
+}
+
+/// Reduce an image to a few gray levels with Floyd–Steinberg dithering
+fn dither_grayscale(img: &DynamicImage, levels: u8) -> DynamicImage {
+    let mut luma = img.to_luma8();
+    image::imageops::dither(&mut luma, &GrayLevels(levels));
+    DynamicImage::ImageLuma8(luma)
+}
+
+/// Rotate, then mirror an image
+fn apply_orientation(img: DynamicImage, rotate: Rotation, flip: Option<Flip>) -> DynamicImage {
+    let img = match rotate {
+        Rotation::None => img,
+        Rotation::Rotate90 => img.rotate90(),
+        Rotation::Rotate180 => img.rotate180(),
+        Rotation::Rotate270 => img.rotate270(),
+    };
+
+    match flip {
+        Some(Flip::Horizontal) => img.fliph(),
+        Some(Flip::Vertical) => img.flipv(),
+        None => img,
+    }
+}
+
+/// Apply brightness and contrast adjustments given in percent
+fn adjust_levels(img: DynamicImage, brightness: i32, contrast: i32) -> DynamicImage {
+    let img = if brightness != 0 {
+        img.brighten(brightness * 255 / 100)
+    } else {
+        img
+    };
+
+    if contrast != 0 {
+        img.adjust_contrast(contrast as f32)
+    } else {
+        img
     }
+}
+
+/// Largest thumbnail side used for BlurHash computation
+const BLURHASH_MAX_DIMENSION: u32 = 64;
+
+/// Compute a BlurHash placeholder from a small thumbnail of the image
+fn compute_blurhash(img: &DynamicImage) -> Option<String> {
+    let thumbnail = img.thumbnail(BLURHASH_MAX_DIMENSION, BLURHASH_MAX_DIMENSION).to_rgba8();
+    let (width, height) = thumbnail.dimensions();
+
+    // 4 components along the long side, 3 along the short one
+    let (components_x, components_y) = if width >= height { (4, 3) } else { (3, 4) };
 
-    ImageFormat::Avif
+    blurhash::encode(components_x, components_y, width, height, thumbnail.as_raw()).ok()
 }
 
 /// Compress image to JPEG format
 fn compress_jpeg(
     img: &DynamicImage,
     quality: u8,
-    grayscale: bool,
+    subsampling: ChromaSubsampling,
 ) -> Result<Vec<u8>, CompressionError> {
-    let processed_img = if grayscale {
-        img.grayscale()
+    // JPEG has no alpha channel (rasterized SVGs and PNGs often do)
+    let processed_img = if img.color().has_alpha() {
+        Cow::Owned(flatten_alpha(img))
     } else {
-        img.clone()
+        Cow::Borrowed(img)
+    };
+
+    let (width, height) = match (u16::try_from(processed_img.width()), u16::try_from(processed_img.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => {
+            return Err(CompressionError::ImageError(format!(
+                "{}x{} exceeds the JPEG size limit",
+                processed_img.width(),
+                processed_img.height()
+            )))
+        }
     };
 
     let mut buffer = Vec::new();
-    let mut cursor = Cursor::new(&mut buffer);
     
-    // Create JPEG encoder with quality
-    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut cursor, quality);
+    // Create JPEG encoder with quality; subsampling only applies to color
+    let mut encoder = jpeg_encoder::Encoder::new(&mut buffer, quality);
+    encoder.set_sampling_factor(subsampling.sampling_factor());
     
     // Encode the image
+    let result = match processed_img.as_ref() {
+        DynamicImage::ImageLuma8(gray) => encoder.encode(gray.as_raw(), width, height, jpeg_encoder::ColorType::Luma),
+        other => encoder.encode(other.to_rgb8().as_raw(), width, height, jpeg_encoder::ColorType::Rgb),
+    };
+    result.map_err(|e| CompressionError::ImageError(e.to_string()))?;
+
+    Ok(buffer)
+}
+
+/// Compress image to lossy WebP format
+fn compress_webp(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, CompressionError> {
+    // libwebp takes 8-bit RGB or RGBA
+    let (width, height) = (img.width(), img.height());
+    let encode = |pixels: &[u8], layout| {
+        webp::Encoder::new(pixels, layout, width, height).encode_simple(false, quality as f32)
+    };
+    let result = match img {
+        DynamicImage::ImageRgb8(rgb) => encode(rgb.as_raw(), webp::PixelLayout::Rgb),
+        DynamicImage::ImageRgba8(rgba) => encode(rgba.as_raw(), webp::PixelLayout::Rgba),
+        other if other.color().has_alpha() => encode(other.to_rgba8().as_raw(), webp::PixelLayout::Rgba),
+        other => encode(other.to_rgb8().as_raw(), webp::PixelLayout::Rgb),
+    };
+    let encoded = result.map_err(|e| CompressionError::ImageError(format!("WebP encoding failed: {:?}", e)))?;
+
+    Ok(encoded.to_vec())
+}
+
+/// Compress image to lossless WebP format
+fn compress_webp_lossless(img: &DynamicImage) -> Result<Vec<u8>, CompressionError> {
+    // The WebP encoder only accepts 8-bit Luma/Rgb variants
+    let processed_img = match img {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageRgb8(_)
+        | DynamicImage::ImageRgba8(_) => Cow::Borrowed(img),
+        other => Cow::Owned(DynamicImage::ImageRgba8(other.to_rgba8())),
+    };
+
+    let mut buffer = Vec::new();
+    let encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut buffer);
+
     encoder.encode(
         processed_img.as_bytes(),
         processed_img.width(),
@@ -117,117 +868,542 @@ fn compress_jpeg(
     Ok(buffer)
 }
 
-/// Compress image to AVIF format
-#[cfg(feature = "avif")]
-fn compress_avif(
-    img: &DynamicImage,
-    quality: u8,
-    grayscale: bool,
-) -> Result<Vec<u8>, CompressionError> {
-    let processed_img = if grayscale {
-        img.grayscale()
-    } else {
-        img.clone()
-    };
-
-    let rgba = processed_img.to_rgba8();
-    let (width, height) = rgba.dimensions();
-
-    // Convert to RGBA8 format expected by ravif
-    let rgba_data: Vec<RGBA8> = rgba
-        .chunks_exact(4)
-        .map(|chunk| RGBA8::new(chunk[0], chunk[1], chunk[2], chunk[3]))
-        .collect();
+/// Compress image to PNG format
+fn compress_png(img: &DynamicImage, config: &Config) -> Result<Vec<u8>, CompressionError> {
+    #[cfg(feature = "quantize")]
+    if let Some(quantized) = quantize_png(img, config) {
+        return Ok(optimize_png(quantized, config));
+    }
 
-    // Optimize AVIF encoding for better quality:
-    // - Quality value (0-100 scale)
-    // - Slower speed (0=slowest/best, 10=fastest/worst)
-    let result = Encoder::new()
-        .with_quality((quality as f32).min(100.0))
-        .with_speed(2)  // Slower = better quality (was 4)
-        .with_bit_depth(BitDepth::Eight)
-        .with_alpha_color_mode(AlphaColorMode::UnassociatedDirty)
-        .encode_rgba(imgref::Img::new(rgba_data.as_slice(), width as usize, height as usize))
+    let mut buffer = Vec::new();
+    img.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)
         .map_err(|e| CompressionError::ImageError(e.to_string()))?;
 
-    Ok(result.avif_file)
+    Ok(optimize_png(buffer, config))
 }
 
-/// Compress image to AVIF format (fallback without ravif)
-#[cfg(not(feature = "avif"))]
-fn compress_avif(
-    img: &DynamicImage,
-    quality: u8,
-    grayscale: bool,
-) -> Result<Vec<u8>, CompressionError> {
-    // Fallback to JPEG if AVIF not available
-    compress_jpeg(img, quality, grayscale)
-}
+/// Quantize to a dithered 256 color palette and encode as an indexed PNG.
+/// Returns None for images that already have few colors or would drop
+/// below `png_quantize_min_quality`.
+#[cfg(feature = "quantize")]
+fn quantize_png(img: &DynamicImage, config: &Config) -> Option<Vec<u8>> {
+    const PALETTE_SIZE: usize = 256;
 
-/// Main compression function
-pub async fn compress(
-    image_data: &[u8],
-    use_avif: bool,
-    grayscale: bool,
-    quality: u8,
-    original_size: u64,
-    logger: &Logger,
-) -> Result<CompressionResult, CompressionError> {
-    let config = Config::default();
+    if count_unique_colors(img, PALETTE_SIZE) <= PALETTE_SIZE {
+        return None;
+    }
 
-    logger.debug(
-        "Compression started",
-        &serde_json::json!({
-            "originalSize": original_size,
-            "quality": quality,
-            "useAvif": use_avif,
-            "grayscale": grayscale,
-        }),
-    );
+    let rgba = img.to_rgba8();
+    let quantizer = color_quant::NeuQuant::new(10, PALETTE_SIZE, rgba.as_raw());
+    let mut dithered = rgba.clone();
+    image::imageops::dither(&mut dithered, &quantizer);
 
-    // Load image
-    let img = ImageReader::new(Cursor::new(image_data))
-        .with_guessed_format()
-        .map_err(|e| CompressionError::ImageError(e.to_string()))?
-        .decode()
-        .map_err(|e| CompressionError::ImageError(e.to_string()))?;
+    if quantization_quality(&rgba, &dithered) < config.png_quantize_min_quality {
+        return None;
+    }
 
-    // Calculate dimensions
-    let (orig_width, orig_height) = img.dimensions();
-    let (new_width, new_height) = calculate_dimensions(orig_width, orig_height, config.max_width);
+    let indices = image::imageops::index_colors(&dithered, &quantizer);
+    let palette = quantizer.color_map_rgba();
+    let rgb: Vec<u8> = palette.chunks_exact(4).flat_map(|c| [c[0], c[1], c[2]]).collect();
+    let alpha: Vec<u8> = palette.chunks_exact(4).map(|c| c[3]).collect();
 
-    logger.debug(
-        "Image dimensions",
-        &serde_json::json!({
-            "original": {"width": orig_width, "height": orig_height},
-            "resized": {"width": new_width, "height": new_height},
-        }),
-    );
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(rgb);
+    encoder.set_trns(alpha);
+    let mut writer = encoder.write_header().ok()?;
+    writer.write_image_data(indices.as_raw()).ok()?;
+    writer.finish().ok()?;
 
-    // Resize image
-    let resized = img.resize_exact(
-        new_width,
-        new_height,
-        image::imageops::FilterType::Lanczos3,
+    Some(buffer)
+}
+
+/// Score a quantized image against the original on a 0-100 scale
+/// (15 dB PSNR or worse is 0, 35 dB or better is 100)
+#[cfg(feature = "quantize")]
+fn quantization_quality(original: &image::RgbaImage, quantized: &image::RgbaImage) -> u8 {
+    let squared_error: f64 = original
+        .as_raw()
+        .iter()
+        .zip(quantized.as_raw())
+        .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+        .sum();
+    let mse = squared_error / original.as_raw().len().max(1) as f64;
+    if mse == 0.0 {
+        return 100;
+    }
+
+    let psnr = 10.0 * (255.0f64 * 255.0 / mse).log10();
+    ((psnr - 15.0) * 5.0).clamp(0.0, 100.0) as u8
+}
+
+/// Re-optimize a PNG with oxipng, keeping the original if it doesn't shrink
+#[cfg(feature = "oxipng")]
+fn optimize_png(data: Vec<u8>, config: &Config) -> Vec<u8> {
+    let mut options = oxipng::Options::from_preset(config.png_optimization_level);
+    // oxipng stops trying further filters/strategies once the budget is spent
+    options.timeout = Some(config.png_optimization_timeout);
+
+    match oxipng::optimize_from_memory(&data, &options) {
+        Ok(optimized) if optimized.len() < data.len() => optimized,
+        _ => data,
+    }
+}
+
+/// PNG re-optimization (no-op without oxipng)
+#[cfg(not(feature = "oxipng"))]
+fn optimize_png(data: Vec<u8>, _config: &Config) -> Vec<u8> {
+    data
+}
+
+/// Count unique colors, stopping once the count exceeds `limit`
+fn count_unique_colors(img: &DynamicImage, limit: usize) -> usize {
+    let mut colors = HashSet::new();
+
+    for (_, _, pixel) in img.pixels() {
+        colors.insert(pixel.0);
+        if colors.len() > limit {
+            break;
+        }
+    }
+
+    colors.len()
+}
+
+/// Check whether an image looks like flat graphics (logos, UI screenshots)
+/// that are better served by a lossless encode
+fn is_lossless_candidate(
+    img: &DynamicImage,
+    source_format: Option<ImageFormat>,
+    original_size: u64,
+    config: &Config,
+) -> bool {
+    if source_format == Some(ImageFormat::Png) && original_size <= config.lossless_max_png_size {
+        return true;
+    }
+
+    count_unique_colors(img, config.lossless_max_colors) <= config.lossless_max_colors
+}
+
+/// Map a 0-100 quality to a butteraugli distance (libjxl's JxlEncoderDistanceFromQuality)
+#[cfg(feature = "jxl")]
+fn jxl_distance(quality: u8) -> f32 {
+    let quality = quality as f32;
+    if quality >= 100.0 {
+        0.0
+    } else if quality >= 30.0 {
+        0.1 + (100.0 - quality) * 0.09
+    } else {
+        53.0 / 3000.0 * quality * quality - 23.0 / 20.0 * quality + 25.0
+    }
+}
+
+/// Compress image to JPEG XL format
+#[cfg(feature = "jxl")]
+fn compress_jxl(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, CompressionError> {
+    let rgba = img.to_rgba8();
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .has_alpha(true)
+        .quality(jxl_distance(quality))
+        .build()
+        .map_err(|e| CompressionError::ImageError(e.to_string()))?;
+
+    let result: jpegxl_rs::encode::EncoderResult<u8> = encoder
+        .encode(rgba.as_raw(), rgba.width(), rgba.height())
+        .map_err(|e| CompressionError::ImageError(e.to_string()))?;
+
+    Ok(result.data)
+}
+
+/// Losslessly repack a JPEG as JPEG XL, keeping the original DCT coefficients
+#[cfg(feature = "jxl")]
+fn transcode_jpeg_to_jxl(
+    image_data: &Bytes,
+    original_size: u64,
+    quality: u8,
+    logger: &Logger,
+) -> Result<CompressionResult, CompressionError> {
+    let encode_start = Instant::now();
+    let mut encoder = jpegxl_rs::encoder_builder()
+        .build()
+        .map_err(|e| CompressionError::ImageError(e.to_string()))?;
+    let result: jpegxl_rs::encode::EncoderResult<u8> = encoder
+        .encode_jpeg(&image_data[..])
+        .map_err(|e| CompressionError::ImageError(e.to_string()))?;
+    let encode_ms = encode_start.elapsed().as_millis() as u64;
+
+    let compressed_size = result.data.len() as u64;
+    let (width, height) = probe_dimensions(image_data).unwrap_or((0, 0));
+
+    if compressed_size > original_size {
+        logger.log_compression_process(
+            "unknown",
+            original_size,
+            Some(compressed_size),
+            Some(0),
+            quality,
+            "jxl",
+            encode_ms,
+            Some("bypassed-larger"),
+        );
+
+        return Ok(CompressionResult {
+            data: image_data.clone(),
+            format: None,
+            bytes_saved: 0,
+            decode_ms: 0,
+            resize_ms: 0,
+            encode_ms,
+            original_width: width,
+            original_height: height,
+            output_width: width,
+            output_height: height,
+            bypass_reason: Some("larger-after-compression"),
+            blurhash: None,
+            animation_dropped: false,
+            format_fallback: None,
+        });
+    }
+
+    let bytes_saved = original_size as i64 - compressed_size as i64;
+    logger.log_compression_process(
+        "unknown",
+        original_size,
+        Some(compressed_size),
+        Some(bytes_saved as u64),
+        quality,
+        "jxl",
+        encode_ms,
+        None,
     );
 
-    // Select output format
-    let output_format = select_format(use_avif, new_height, &config);
+    Ok(CompressionResult {
+        data: Bytes::from(result.data),
+        format: Some(OutputFormat::Jxl),
+        bytes_saved,
+        decode_ms: 0,
+        resize_ms: 0,
+        encode_ms,
+        original_width: width,
+        original_height: height,
+        output_width: width,
+        output_height: height,
+        bypass_reason: None,
+        blurhash: None,
+        animation_dropped: false,
+        format_fallback: None,
+    })
+}
+
+/// Compress image to AVIF format
+#[cfg(feature = "avif")]
+fn compress_avif(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, CompressionError> {
+    let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+
+    // Convert to RGBA8 format expected by ravif
+    let rgba_data: Vec<RGBA8> = rgba
+        .chunks_exact(4)
+        .map(|chunk| RGBA8::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+        .collect();
 
-    // Calculate effective quality for grayscale
-    let effective_quality = if grayscale {
-        quality.clamp(config.grayscale_quality_range.0, config.grayscale_quality_range.1)
+    // Optimize AVIF encoding for better quality:
+    // - Quality value (0-100 scale)
+    // - Slower speed (0=slowest/best, 10=fastest/worst)
+    let result = Encoder::new()
+        .with_quality((quality as f32).min(100.0))
+        .with_speed(2)  // Slower = better quality (was 4)
+        .with_bit_depth(BitDepth::Eight)
+        .with_alpha_color_mode(AlphaColorMode::UnassociatedDirty)
+        .encode_rgba(imgref::Img::new(rgba_data.as_slice(), width as usize, height as usize))
+        .map_err(|e| CompressionError::ImageError(e.to_string()))?;
+
+    Ok(result.avif_file)
+}
+
+/// Compress image to AVIF format (fallback without ravif)
+#[cfg(not(feature = "avif"))]
+fn compress_avif(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, CompressionError> {
+    // Fallback to JPEG if AVIF not available
+    compress_jpeg(img, quality, ChromaSubsampling::default())
+}
+
+/// Encode AVIF and JPEG side by side, returning `(avif, jpeg)`
+fn encode_avif_and_jpeg(
+    img: &DynamicImage,
+    quality: u8,
+    subsampling: ChromaSubsampling,
+) -> Result<(Vec<u8>, Vec<u8>), CompressionError> {
+    std::thread::scope(|scope| {
+        let jpeg = scope.spawn(|| compress_jpeg(img, quality, subsampling));
+        let avif = compress_avif(img, quality)?;
+        let jpeg = jpeg
+            .join()
+            .map_err(|_| CompressionError::ImageError("JPEG encoder panicked".to_string()))??;
+        Ok((avif, jpeg))
+    })
+}
+
+/// Bail out between pipeline stages once the caller has given up
+fn check_cancelled(cancel: &AtomicBool) -> Result<(), CompressionError> {
+    if cancel.load(Ordering::Relaxed) {
+        return Err(CompressionError::Cancelled);
+    }
+
+    Ok(())
+}
+
+/// Main compression function. Stops early with `Cancelled` once `cancel`
+/// is set (e.g. after the caller's deadline expired).
+pub async fn compress(
+    image_data: &Bytes,
+    params: &CompressParams,
+    config: &Config,
+    cancel: &AtomicBool,
+    logger: &Logger,
+) -> Result<CompressionResult, CompressionError> {
+    let CompressParams {
+        use_avif,
+        use_webp,
+        format: requested_format,
+        grayscale,
+        dither,
+        dpr,
+        still,
+        width,
+        height,
+        dual_encode,
+        lqip,
+        brightness,
+        contrast,
+        invert,
+        sharpen,
+        rotate,
+        flip,
+        quality,
+        original_size,
+    } = *params;
+    let dither = grayscale && dither;
+
+    logger.debug(
+        "Compression started",
+        &serde_json::json!({
+            "originalSize": original_size,
+            "quality": quality,
+            "useAvif": use_avif,
+            "useWebp": use_webp,
+            "format": requested_format.map(|f| f.as_str()),
+            "grayscale": grayscale,
+            "dither": dither,
+            "dpr": dpr,
+            "still": still,
+            "width": width,
+            "height": height,
+            "dualEncode": dual_encode,
+            "lqip": lqip,
+            "brightness": brightness,
+            "contrast": contrast,
+            "invert": invert,
+            "sharpen": sharpen,
+            "rotate": format!("{:?}", rotate),
+            "flip": flip.map(|f| format!("{:?}", f)),
+        }),
+    );
+
+    // Explicit sizes win; otherwise high-density displays get a
+    // proportionally larger width budget
+    let (max_width, max_height) = size_limits(params, config);
+    let config = &Config {
+        max_width,
+        max_height,
+        ..config.clone()
+    };
+
+    // Decoding an APNG would silently flatten it to its first frame
+    if !lqip && is_animated_png(image_data) {
+        return Ok(passthrough_result(image_data, "animated-png"));
+    }
+
+    // The image crate can't decode HEIC on its own
+    if !cfg!(feature = "heif") && is_heif(image_data) {
+        return Ok(passthrough_result(image_data, "unsupported-heif"));
+    }
+
+    // JPEG sources can be transcoded to JXL losslessly without a decode/re-encode
+    #[cfg(feature = "jxl")]
+    if requested_format == Some(OutputFormat::Jxl) && !lqip && image::guess_format(image_data).ok() == Some(ImageFormat::Jpeg) {
+        return transcode_jpeg_to_jxl(image_data, original_size, quality, logger);
+    }
+
+    // The GIF decoder only reads the first frame, so a still is just a normal decode
+    let animation_dropped = still && is_animated_gif(image_data);
+
+    // Load image
+    let decode_start = Instant::now();
+    let quarter_turn = matches!(rotate, Rotation::Rotate90 | Rotation::Rotate270);
+    let (img, source_format) = match decode_source(image_data, config, quarter_turn) {
+        Ok(decoded) => decoded,
+        #[cfg(feature = "svg")]
+        Err(CompressionError::InvalidSvg(e)) => {
+            logger.debug("SVG rasterization failed", &serde_json::json!({ "error": e }));
+            return Ok(passthrough_result(image_data, "invalid-svg"));
+        }
+        Err(e) => return Err(e),
+    };
+    let decode_ms = decode_start.elapsed().as_millis() as u64;
+    check_cancelled(cancel)?;
+
+    // Calculate dimensions (JPEGs may have been decoded at reduced scale)
+    let (orig_width, orig_height) = match source_format {
+        Some(ImageFormat::Jpeg) => probe_dimensions(image_data).unwrap_or_else(|| img.dimensions()),
+        _ => img.dimensions(),
+    };
+    // Size limits apply to the image as it will be displayed, after rotation
+    let (oriented_width, oriented_height) = if quarter_turn {
+        (orig_height, orig_width)
+    } else {
+        (orig_width, orig_height)
+    };
+    let (new_width, new_height) =
+        calculate_dimensions(oriented_width, oriented_height, config.max_width, config.max_height);
+
+    logger.debug(
+        "Image dimensions",
+        &serde_json::json!({
+            "original": {"width": orig_width, "height": orig_height},
+            "resized": {"width": new_width, "height": new_height},
+        }),
+    );
+
+    // Resize image
+    let resize_start = Instant::now();
+    // Rotating the smaller, resized image is cheaper than rotating the source
+    let resized = if quarter_turn {
+        resize_image(&img, new_height, new_width)
+    } else {
+        resize_image(&img, new_width, new_height)
+    };
+    let resized = apply_orientation(resized, rotate, flip);
+    let resize_ms = resize_start.elapsed().as_millis() as u64;
+    check_cancelled(cancel)?;
+
+    // Downscaling leaves fine line art soft; dithering would only add noise to the halos
+    let sharpen = sharpen.unwrap_or(config.sharpen_enabled)
+        && !dither
+        && (new_width, new_height) != (oriented_width, oriented_height);
+    let resized = if sharpen {
+        resized.unsharpen(config.sharpen_sigma, config.sharpen_threshold)
+    } else {
+        resized
+    };
+
+    let resized = adjust_levels(resized, brightness, contrast);
+
+    // Convert once here so the encoders can borrow the resized buffer as-is.
+    // Dithered output is single-channel and always goes out as JPEG
+    let mut resized = if dither {
+        dither_grayscale(&resized, config.dither_levels)
+    } else if grayscale {
+        resized.grayscale()
+    } else {
+        resized
+    };
+
+    if invert {
+        resized.invert();
+    }
+
+    let blurhash = if config.blurhash_enabled && !lqip {
+        compute_blurhash(&resized)
+    } else {
+        None
+    };
+
+    // Select output format; dithered output defaults to a single-channel JPEG
+    let mut output_format = if lqip || (dither && requested_format.is_none()) {
+        OutputFormat::Jpeg
+    } else {
+        select_format(use_avif, use_webp, requested_format, new_height, config)
+    };
+    let format_fallback = requested_format.filter(|&format| !lqip && format != output_format);
+
+    // Calculate effective quality for placeholders and grayscale
+    let effective_quality = if lqip {
+        config.lqip_quality
+    } else if grayscale {
+        grayscale_quality(quality, config.grayscale_quality_range)
     } else {
         quality
     };
 
+    logger.debug(
+        "Encode settings",
+        &serde_json::json!({
+            "format": output_format.as_str(),
+            "quality": effective_quality,
+            // Grayscale JPEGs have no chroma to subsample
+            "jpegSubsampling": if resized.color().has_color() { config.jpeg_subsampling.as_str() } else { "none" },
+        }),
+    );
+
     // Compress based on format
-    let compressed_data = match output_format {
-        ImageFormat::Avif => compress_avif(&resized, effective_quality, grayscale)?,
-        ImageFormat::Jpeg => compress_jpeg(&resized, effective_quality, grayscale)?,
-        _ => compress_jpeg(&resized, effective_quality, grayscale)?,
+    let encode_start = Instant::now();
+    let mut compressed_data = match output_format {
+        OutputFormat::Avif if dual_encode && requested_format.is_none() => {
+            let (avif_data, jpeg_data) = encode_avif_and_jpeg(&resized, effective_quality, config.jpeg_subsampling)?;
+            let avif_wins = avif_data.len() <= jpeg_data.len();
+
+            logger.debug(
+                "Dual encode",
+                &serde_json::json!({
+                    "avifSize": avif_data.len(),
+                    "jpegSize": jpeg_data.len(),
+                    "winner": if avif_wins { "avif" } else { "jpeg" },
+                }),
+            );
+
+            if avif_wins {
+                avif_data
+            } else {
+                output_format = OutputFormat::Jpeg;
+                jpeg_data
+            }
+        }
+        OutputFormat::Avif => compress_avif(&resized, effective_quality)?,
+        OutputFormat::Jpeg => compress_jpeg(&resized, effective_quality, config.jpeg_subsampling)?,
+        OutputFormat::WebP => compress_webp(&resized, effective_quality)?,
+        OutputFormat::Png => compress_png(&resized, config)?,
+        #[cfg(feature = "jxl")]
+        OutputFormat::Jxl => compress_jxl(&resized, effective_quality)?,
     };
 
+    check_cancelled(cancel)?;
+
+    // Flat graphics often come out smaller (and sharper) as lossless WebP,
+    // for clients that can decode it
+    if use_webp && !dither && !lqip && requested_format.is_none() && is_lossless_candidate(&resized, source_format, original_size, config) {
+        let lossless_data = compress_webp_lossless(&resized)?;
+        let lossless_wins = lossless_data.len() < compressed_data.len();
+
+        logger.debug(
+            "Lossless WebP candidate",
+            &serde_json::json!({
+                "losslessSize": lossless_data.len(),
+                "lossySize": compressed_data.len(),
+                "winner": if lossless_wins { OutputFormat::WebP.as_str() } else { output_format.as_str() },
+            }),
+        );
+
+        if lossless_wins {
+            compressed_data = lossless_data;
+            output_format = OutputFormat::WebP;
+        }
+    }
+
+    let encode_ms = encode_start.elapsed().as_millis() as u64;
+
     let compressed_size = compressed_data.len() as u64;
     let bytes_saved = original_size as i64 - compressed_size as i64;
 
@@ -239,38 +1415,56 @@ pub async fn compress(
             Some(compressed_size),
             Some(0),
             quality,
-            &format!("{:?}", output_format),
+            output_format.as_str(),
+            encode_ms,
             Some("bypassed-larger"),
         );
 
-        // Return original data
+        // Return original data (a refcount bump, not a copy)
         return Ok(CompressionResult {
-            data: image_data.to_vec(),
-            format: "original".to_string(),
+            data: image_data.clone(),
+            format: None,
             bytes_saved: 0,
+            decode_ms,
+            resize_ms,
+            encode_ms,
+            original_width: orig_width,
+            original_height: orig_height,
+            output_width: orig_width,
+            output_height: orig_height,
+            bypass_reason: Some("larger-after-compression"),
+            blurhash,
+            animation_dropped,
+            format_fallback,
         });
     }
 
-    let format_str = match output_format {
-        ImageFormat::Avif => "avif",
-        ImageFormat::Jpeg => "jpeg",
-        _ => "jpeg",
-    };
-
     logger.log_compression_process(
         "unknown",
         original_size,
         Some(compressed_size),
         Some(bytes_saved as u64),
         quality,
-        format_str,
+        output_format.as_str(),
+        encode_ms,
         None,
     );
 
     Ok(CompressionResult {
-        data: compressed_data,
-        format: format_str.to_string(),
+        data: Bytes::from(compressed_data),
+        format: Some(output_format),
         bytes_saved,
+        decode_ms,
+        resize_ms,
+        encode_ms,
+        original_width: orig_width,
+        original_height: orig_height,
+        output_width: new_width,
+        output_height: new_height,
+        bypass_reason: None,
+        blurhash,
+        animation_dropped,
+        format_fallback,
     })
 }
 
@@ -278,13 +1472,58 @@ pub async fn compress(
 mod tests {
     use super::*;
 
+    /// Default request options for compressing `data`
+    fn params_for(data: &[u8], use_avif: bool) -> CompressParams {
+        CompressParams {
+            use_avif,
+            use_webp: false,
+            format: None,
+            grayscale: false,
+            dither: false,
+            dpr: 1.0,
+            still: false,
+            width: None,
+            height: None,
+            dual_encode: false,
+            lqip: false,
+            brightness: 0,
+            contrast: 0,
+            invert: false,
+            sharpen: None,
+            rotate: Rotation::None,
+            flip: None,
+            quality: 40,
+            original_size: data.len() as u64,
+        }
+    }
+
     #[test]
     fn test_calculate_dimensions() {
         // With max_width = 800
-        assert_eq!(calculate_dimensions(1600, 1200, 800), (800, 600));
-        assert_eq!(calculate_dimensions(800, 600, 800), (800, 600));
-        assert_eq!(calculate_dimensions(400, 300, 800), (400, 300));
-        assert_eq!(calculate_dimensions(200, 150, 800), (200, 150));
+        assert_eq!(calculate_dimensions(1600, 1200, 800, u32::MAX), (800, 600));
+        assert_eq!(calculate_dimensions(800, 600, 800, u32::MAX), (800, 600));
+        assert_eq!(calculate_dimensions(400, 300, 800, u32::MAX), (400, 300));
+        assert_eq!(calculate_dimensions(200, 150, 800, u32::MAX), (200, 150));
+
+        // The tighter of the two bounds wins
+        assert_eq!(calculate_dimensions(1600, 1200, 800, 300), (400, 300));
+        assert_eq!(calculate_dimensions(1600, 1200, 300, 900), (300, 225));
+        assert_eq!(calculate_dimensions(400, 300, 4096, 4096), (400, 300));
+        assert_eq!(calculate_dimensions(4000, 10, 800, u32::MAX), (800, 2));
+    }
+
+    #[test]
+    fn test_effective_max_width() {
+        let config = Config::default();
+
+        assert_eq!(effective_max_width(&config, 1.0), 800);
+        assert_eq!(effective_max_width(&config, 1.25), 1000);
+        assert_eq!(effective_max_width(&config, 2.0), 1200);
+        assert_eq!(effective_max_width(&config, 3.0), 1200);
+
+        // The cap never shrinks the base width
+        let wide = Config { max_width: 1600, ..Config::default() };
+        assert_eq!(effective_max_width(&wide, 2.0), 1600);
     }
 
     #[test]
@@ -292,14 +1531,805 @@ mod tests {
         let config = Config::default();
         
         // Client requested JPEG (use_avif = false) → always JPEG
-        assert_eq!(select_format(false, 1000, &config), ImageFormat::Jpeg);
-        assert_eq!(select_format(false, 40000, &config), ImageFormat::Jpeg);
+        assert_eq!(select_format(false, false, None, 1000, &config), OutputFormat::Jpeg);
+        assert_eq!(select_format(false, false, None, 40000, &config), OutputFormat::Jpeg);
         
         // Client requested WebP (use_avif = true) → AVIF if within limits
-        assert_eq!(select_format(true, 1000, &config), ImageFormat::Avif);
+        assert_eq!(select_format(true, false, None, 1000, &config), OutputFormat::Avif);
         
         // Client requested WebP but height exceeds limits → fallback to JPEG
-        assert_eq!(select_format(true, 40000, &config), ImageFormat::Jpeg);
-        assert_eq!(select_format(true, 20000, &config), ImageFormat::Jpeg);
+        assert_eq!(select_format(true, false, None, 40000, &config), OutputFormat::Jpeg);
+        assert_eq!(select_format(true, false, None, 20000, &config), OutputFormat::Jpeg);
+
+        // Client takes WebP but not AVIF → lossy WebP if within limits
+        assert_eq!(select_format(false, true, None, 1000, &config), OutputFormat::WebP);
+        assert_eq!(select_format(false, true, None, 20000, &config), OutputFormat::Jpeg);
+    }
+
+    #[test]
+    fn test_grayscale_quality_clamp() {
+        assert_eq!(grayscale_quality(70, (15, 50)), 50);
+        assert_eq!(grayscale_quality(50, (15, 50)), 50);
+        assert_eq!(grayscale_quality(15, (15, 50)), 15);
+        assert_eq!(grayscale_quality(1, (15, 50)), 15);
+        assert_eq!(grayscale_quality(40, (40, 40)), 40);
+
+        // The full range leaves every quality untouched
+        for quality in [0, 1, 70, 100] {
+            assert_eq!(grayscale_quality(quality, (0, 100)), quality);
+        }
+    }
+
+    #[test]
+    fn test_config_validate_grayscale_range() {
+        let with_range = |range| Config { grayscale_quality_range: range, ..Config::default() };
+
+        assert!(Config::default().validate().is_ok());
+        assert!(with_range((0, 100)).validate().is_ok());
+        assert!(with_range((60, 60)).validate().is_ok());
+        assert!(with_range((60, 40)).validate().is_err());
+        assert!(with_range((10, 101)).validate().is_err());
+    }
+
+    #[test]
+    fn test_select_format_explicit() {
+        let config = Config::default();
+
+        // Explicit formats ignore the jpeg=1 preference
+        assert_eq!(select_format(false, false, Some(OutputFormat::Avif), 1000, &config), OutputFormat::Avif);
+        assert_eq!(select_format(true, false, Some(OutputFormat::Jpeg), 1000, &config), OutputFormat::Jpeg);
+        assert_eq!(select_format(true, false, Some(OutputFormat::WebP), 1000, &config), OutputFormat::WebP);
+        assert_eq!(select_format(true, false, Some(OutputFormat::Png), 40000, &config), OutputFormat::Png);
+
+        // ...but not the height limits
+        assert_eq!(select_format(true, false, Some(OutputFormat::Avif), 20000, &config), OutputFormat::Jpeg);
+        assert_eq!(select_format(true, false, Some(OutputFormat::WebP), 20000, &config), OutputFormat::Jpeg);
+        assert_eq!(select_format(true, false, Some(OutputFormat::Jpeg), 40000, &config), OutputFormat::Png);
+    }
+
+    #[cfg(feature = "jxl")]
+    #[test]
+    fn test_select_format_jxl() {
+        let config = Config::default();
+        assert_eq!(select_format(true, false, Some(OutputFormat::Jxl), 1000, &config), OutputFormat::Jxl);
+        assert_eq!(select_format(false, false, Some(OutputFormat::Jxl), 40000, &config), OutputFormat::Jxl);
+    }
+
+    #[cfg(feature = "jxl")]
+    #[test]
+    fn test_jxl_distance() {
+        assert_eq!(jxl_distance(100), 0.0);
+        assert!((jxl_distance(90) - 1.0).abs() < 1e-6);
+        assert!(jxl_distance(40) > jxl_distance(90));
+        assert!(jxl_distance(10) > jxl_distance(40));
+    }
+
+    #[test]
+    fn test_flatten_alpha_onto_white() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([10, 20, 30, 255]) }
+        }));
+        let flattened = flatten_alpha(&img).to_rgb8();
+
+        assert_eq!(flattened.get_pixel(0, 0), &image::Rgb([255, 255, 255]));
+        assert_eq!(flattened.get_pixel(1, 0), &image::Rgb([10, 20, 30]));
+        assert!(compress_jpeg(&img, 40, ChromaSubsampling::S420).is_ok());
+        assert!(compress_jpeg(&img.grayscale(), 40, ChromaSubsampling::S420).is_ok());
+    }
+
+    /// Luma and chroma sampling factors (`0xHV`) from a baseline JPEG's SOF0 segment
+    fn jpeg_sampling_factors(data: &[u8]) -> Vec<u8> {
+        let mut pos = 2;
+        while pos + 4 <= data.len() {
+            let marker = data[pos + 1];
+            let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            if marker == 0xC0 {
+                let components = data[pos + 9] as usize;
+                return (0..components).map(|i| data[pos + 11 + i * 3]).collect();
+            }
+            pos += 2 + length;
+        }
+        Vec::new()
+    }
+
+    #[test]
+    fn test_compress_jpeg_chroma_subsampling() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 128])
+        }));
+
+        for (subsampling, factors) in [
+            (ChromaSubsampling::S420, [0x22, 0x11, 0x11]),
+            (ChromaSubsampling::S422, [0x21, 0x11, 0x11]),
+            (ChromaSubsampling::S444, [0x11, 0x11, 0x11]),
+        ] {
+            let jpeg = compress_jpeg(&img, 40, subsampling).unwrap();
+            assert_eq!(jpeg_sampling_factors(&jpeg), factors, "{}", subsampling.as_str());
+            assert_eq!(image::load_from_memory(&jpeg).unwrap().dimensions(), (64, 48));
+        }
+
+        assert_eq!("4:2:0".parse(), Ok(ChromaSubsampling::S420));
+        assert_eq!("422".parse(), Ok(ChromaSubsampling::S422));
+        assert!("411".parse::<ChromaSubsampling>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compress_sharpen_after_downscale() {
+        let photo = DynamicImage::ImageRgb8(image::RgbImage::from_fn(1600, 1200, |x, y| {
+            let n = (x.wrapping_mul(7919) ^ y.wrapping_mul(104_729)).wrapping_add(x * y);
+            image::Rgb([(n % 256) as u8, (x % 256) as u8, (y % 256) as u8])
+        }));
+        let source = Bytes::from(encode_fixture(&photo, ImageFormat::Jpeg));
+        let encode = |source: Bytes, sharpen| async move {
+            let params = CompressParams { sharpen, ..params_for(&source, false) };
+            compress(&source, &params, &Config::default(), &AtomicBool::new(false), &Logger::default())
+                .await
+                .unwrap()
+                .data
+        };
+
+        let default = encode(source.clone(), None).await;
+        assert_eq!(encode(source.clone(), Some(false)).await, default);
+        assert_ne!(encode(source.clone(), Some(true)).await, default);
+
+        // Nothing to sharpen when the image isn't downscaled
+        let small = Bytes::from(encode_fixture(&photo.crop_imm(0, 0, 400, 300), ImageFormat::Jpeg));
+        assert_eq!(encode(small.clone(), Some(true)).await, encode(small, Some(false)).await);
+    }
+
+    #[tokio::test]
+    async fn test_compress_uses_configured_subsampling() {
+        let photo = DynamicImage::ImageRgb8(image::RgbImage::from_fn(1600, 1200, |x, y| {
+            let n = (x.wrapping_mul(7919) ^ y.wrapping_mul(104_729)).wrapping_add(x * y);
+            image::Rgb([(n % 256) as u8, (x % 256) as u8, (y % 256) as u8])
+        }));
+        let source = Bytes::from(encode_fixture(&photo, ImageFormat::Jpeg));
+        let config = Config { jpeg_subsampling: ChromaSubsampling::S420, ..Config::default() };
+
+        let result = compress(
+            &source,
+            &params_for(&source, false),
+            &config,
+            &AtomicBool::new(false),
+            &Logger::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(result.format, Some(OutputFormat::Jpeg));
+        assert_eq!(jpeg_sampling_factors(&result.data), [0x22, 0x11, 0x11]);
+    }
+
+    #[test]
+    fn test_is_heif() {
+        let heic = b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic";
+        let avif = b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00avifmif1miaf";
+
+        assert!(is_heif(heic));
+        assert!(!is_heif(avif));
+        assert!(!is_heif(b"\x00\x00\x00\x18ftyp"));
+        assert!(!is_heif(b"\xff\xd8\xff\xe0\x00\x10JFIF\x00"));
+    }
+
+    #[cfg(not(feature = "heif"))]
+    #[tokio::test]
+    async fn test_compress_bypasses_heif_without_feature() {
+        let heic = b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic";
+        let result = compress(&Bytes::copy_from_slice(heic), &params_for(heic, true), &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.bypass_reason, Some("unsupported-heif"));
+        assert_eq!(result.data, &heic[..]);
+    }
+
+    #[test]
+    fn test_probe_dimensions() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(123, 45));
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        assert_eq!(probe_dimensions(&png), Some((123, 45)));
+        assert_eq!(probe_dimensions(b"not an image"), None);
+    }
+
+    /// Encode a two-frame APNG
+    fn apng_fixture() -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buffer, 4, 4);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(2, 0).unwrap();
+
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0u8; 4 * 4 * 3]).unwrap();
+        writer.write_image_data(&[255u8; 4 * 4 * 3]).unwrap();
+        writer.finish().unwrap();
+
+        buffer
+    }
+
+    #[test]
+    fn test_is_animated_png() {
+        assert!(is_animated_png(&apng_fixture()));
+
+        let mut still = Vec::new();
+        DynamicImage::ImageRgb8(image::RgbImage::new(4, 4))
+            .write_to(&mut Cursor::new(&mut still), ImageFormat::Png)
+            .unwrap();
+        assert!(!is_animated_png(&still));
+
+        // Truncated data must not panic
+        assert!(!is_animated_png(&apng_fixture()[..20]));
+        assert!(!is_animated_png(b"GIF89a"));
+    }
+
+    #[tokio::test]
+    async fn test_compress_passes_apng_through() {
+        let apng = apng_fixture();
+        let result = compress(&Bytes::copy_from_slice(&apng), &params_for(&apng, true), &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.bypass_reason, Some("animated-png"));
+        assert_eq!(result.data, apng);
+    }
+
+    #[test]
+    fn test_lossless_candidate() {
+        let config = Config::default();
+
+        // Two-color graphic → candidate regardless of source format
+        let flat = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, _| {
+            if x < 32 { image::Rgb([255, 255, 255]) } else { image::Rgb([0, 0, 0]) }
+        }));
+        assert!(is_lossless_candidate(&flat, Some(ImageFormat::Jpeg), 500_000, &config));
+
+        // Photo-like gradient → candidate only when it's a small PNG
+        let photo = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        }));
+        assert!(!is_lossless_candidate(&photo, Some(ImageFormat::Jpeg), 50_000, &config));
+        assert!(is_lossless_candidate(&photo, Some(ImageFormat::Png), 50_000, &config));
+        assert!(!is_lossless_candidate(&photo, Some(ImageFormat::Png), 500_000, &config));
+    }
+
+    #[cfg(feature = "oxipng")]
+    #[test]
+    fn test_optimize_png_preserves_pixels() {
+        let config = Config::default();
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(128, 128, |x, y| {
+            image::Rgba([(x * 2) as u8, (y * 2) as u8, 128, if (x + y) % 3 == 0 { 0 } else { 255 }])
+        }));
+
+        let mut unoptimized = Vec::new();
+        img.write_to(&mut Cursor::new(&mut unoptimized), ImageFormat::Png).unwrap();
+
+        let optimized = optimize_png(unoptimized.clone(), &config);
+        assert!(optimized.len() <= unoptimized.len());
+
+        let decoded = image::load_from_memory(&optimized).unwrap();
+        assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+    }
+
+    #[cfg(feature = "svg")]
+    #[tokio::test]
+    async fn test_compress_rasterizes_svg() {
+        // Vector files worth rasterizing are typically large path-heavy documents
+        let shapes: String = (0..2000)
+            .map(|i| format!(r##"<circle cx="{}" cy="{}" r="12" fill="#{:06x}"/>"##, (i * 37) % 1600, (i * 53) % 800, i * 8191 % 0xffffff))
+            .collect();
+        let svg = format!(
+            r##"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="1600" height="800" viewBox="0 0 1600 800">
+  <rect width="1600" height="800" fill="#3366cc"/>{}
+</svg>"##,
+            shapes
+        );
+        let svg = svg.as_bytes();
+
+        let result = compress(&Bytes::copy_from_slice(svg), &params_for(svg, false), &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.bypass_reason, None);
+        assert!(result.format.is_some());
+        assert_eq!((result.output_width, result.output_height), (800, 400));
+        assert!(image::load_from_memory(&result.data).is_ok());
+    }
+
+    #[cfg(feature = "svg")]
+    #[tokio::test]
+    async fn test_compress_svg_fallbacks() {
+        let external = br#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="100" height="100"><image xlink:href="http://169.254.169.254/x.png" width="100" height="100"/></svg>"#;
+        let huge = br#"<svg xmlns="http://www.w3.org/2000/svg" width="100000" height="100000"></svg>"#;
+        let broken = b"<svg xmlns='http://www.w3.org/2000/svg'><rect";
+
+        // External references are simply not resolved
+        let result = compress(&Bytes::copy_from_slice(external), &params_for(external, false), &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+        assert_eq!(result.bypass_reason, None);
+
+        for svg in [&huge[..], &broken[..]] {
+            let result = compress(&Bytes::copy_from_slice(svg), &params_for(svg, false), &Config::default(), &AtomicBool::new(false), &Logger::default())
+                .await
+                .unwrap();
+            assert_eq!(result.bypass_reason, Some("invalid-svg"));
+            assert_eq!(result.data, svg);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compress_flat_graphic_as_lossless_webp() {
+        let flat = DynamicImage::ImageRgb8(image::RgbImage::from_fn(600, 400, |x, y| {
+            if (x / 50 + y / 50) % 2 == 0 { image::Rgb([240, 240, 240]) } else { image::Rgb([30, 60, 200]) }
+        }));
+        let mut source = Vec::new();
+        flat.write_to(&mut Cursor::new(&mut source), ImageFormat::Bmp).unwrap();
+
+        let params = CompressParams { use_webp: true, ..params_for(&source, false) };
+        let result = compress(&Bytes::copy_from_slice(&source), &params, &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.format, Some(OutputFormat::WebP));
+        let decoded = image::load_from_memory(&result.data).unwrap();
+        assert_eq!(decoded.to_rgb8(), flat.to_rgb8());
+
+        // Never for clients without WebP support
+        let result = compress(&Bytes::copy_from_slice(&source), &params_for(&source, false), &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+        assert_eq!(result.format, Some(OutputFormat::Jpeg));
+    }
+
+    #[tokio::test]
+    async fn test_compress_photo_as_lossy_webp() {
+        let mut seed: u32 = 11;
+        let noisy = DynamicImage::ImageRgb8(image::RgbImage::from_fn(600, 400, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            image::Rgb([(x / 3) as u8, (y / 2) as u8, (seed >> 24) as u8])
+        }));
+        let mut source = Vec::new();
+        noisy.write_to(&mut Cursor::new(&mut source), ImageFormat::Png).unwrap();
+
+        let params = CompressParams { use_webp: true, ..params_for(&source, false) };
+        let result = compress(&Bytes::copy_from_slice(&source), &params, &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.format, Some(OutputFormat::WebP));
+        let decoded = image::load_from_memory(&result.data).unwrap();
+        assert_eq!(decoded.dimensions(), (600, 400));
+        // Lossy, so far smaller than the lossless encode of the same pixels
+        assert!(result.data.len() * 4 < compress_webp_lossless(&noisy).unwrap().len());
+    }
+
+    #[test]
+    fn test_dither_grayscale_levels() {
+        let gradient = DynamicImage::ImageRgb8(image::RgbImage::from_fn(256, 16, |x, _| {
+            image::Rgb([x as u8, x as u8, x as u8])
+        }));
+
+        let dithered = dither_grayscale(&gradient, 4);
+        let luma = dithered.as_luma8().unwrap();
+
+        let levels: HashSet<u8> = luma.pixels().map(|p| p.0[0]).collect();
+        assert!(levels.iter().all(|l| [0, 85, 170, 255].contains(l)));
+        assert_eq!(levels.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_compress_dithered_grayscale_is_single_channel() {
+        // Noise keeps the lossless source large enough that the JPEG is kept
+        let mut seed: u32 = 7;
+        let noisy = DynamicImage::ImageRgb8(image::RgbImage::from_fn(1000, 600, |_, _| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let v = (seed >> 16) as u8;
+            image::Rgb([v, v.wrapping_add(40), v / 2])
+        }));
+        let mut source = Vec::new();
+        noisy.write_to(&mut Cursor::new(&mut source), ImageFormat::Png).unwrap();
+
+        let params = CompressParams {
+            grayscale: true,
+            dither: true,
+            ..params_for(&source, true)
+        };
+        let result = compress(&Bytes::copy_from_slice(&source), &params, &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.format, Some(OutputFormat::Jpeg));
+        let decoded = image::load_from_memory(&result.data).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+        assert_eq!(decoded.dimensions(), (800, 480));
+    }
+
+    #[tokio::test]
+    async fn test_compress_grayscale_converts_before_encoding() {
+        let mut seed: u32 = 11;
+        let noisy = DynamicImage::ImageRgb8(image::RgbImage::from_fn(1000, 600, |_, _| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let v = (seed >> 16) as u8;
+            image::Rgb([v, 255 - v, v / 2])
+        }));
+        let mut source = Vec::new();
+        noisy.write_to(&mut Cursor::new(&mut source), ImageFormat::Png).unwrap();
+
+        let params = CompressParams {
+            grayscale: true,
+            ..params_for(&source, false)
+        };
+        let result = compress(&Bytes::copy_from_slice(&source), &params, &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        // A grayscale buffer reaches the JPEG encoder as a single channel
+        assert_eq!(result.format, Some(OutputFormat::Jpeg));
+        assert_eq!(image::load_from_memory(&result.data).unwrap().color(), image::ColorType::L8);
+    }
+
+    #[test]
+    fn test_jpeg_scale_denominator() {
+        assert_eq!(jpeg_scale_denominator(6000, 400), 8);
+        assert_eq!(jpeg_scale_denominator(3200, 800), 4);
+        assert_eq!(jpeg_scale_denominator(1600, 800), 2);
+        assert_eq!(jpeg_scale_denominator(1598, 800), 1);
+        assert_eq!(jpeg_scale_denominator(800, 800), 1);
+        assert_eq!(jpeg_scale_denominator(400, 800), 1);
+    }
+
+    fn encode_fixture(img: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        img.write_to(&mut Cursor::new(&mut data), format).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_decode_source_scales_large_jpegs() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(3300, 200, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }));
+        let config = Config::default();
+
+        let jpeg = encode_fixture(&img, ImageFormat::Jpeg);
+        let (decoded, format) = decode_source(&jpeg, &config, false).unwrap();
+        assert_eq!(format, Some(ImageFormat::Jpeg));
+        assert_eq!(decoded.dimensions(), (825, 50));
+
+        // Other formats keep the full-size decode
+        let png = encode_fixture(&img, ImageFormat::Png);
+        let (decoded, _) = decode_source(&png, &config, false).unwrap();
+        assert_eq!(decoded.dimensions(), (3300, 200));
+
+        // JPEGs too narrow to scale down are decoded at full size
+        let narrow = encode_fixture(&img.crop_imm(0, 0, 1500, 200), ImageFormat::Jpeg);
+        let (decoded, _) = decode_source(&narrow, &config, false).unwrap();
+        assert_eq!(decoded.dimensions(), (1500, 200));
+    }
+
+    #[tokio::test]
+    async fn test_compress_scaled_jpeg_reports_source_dimensions() {
+        let mut seed: u32 = 3;
+        let noisy = DynamicImage::ImageRgb8(image::RgbImage::from_fn(3200, 400, |_, _| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let v = (seed >> 16) as u8;
+            image::Rgb([v, v / 2, 255 - v])
+        }));
+        let jpeg = encode_fixture(&noisy, ImageFormat::Jpeg);
+
+        let result = compress(&Bytes::copy_from_slice(&jpeg), &params_for(&jpeg, false), &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!((result.original_width, result.original_height), (3200, 400));
+        assert_eq!((result.output_width, result.output_height), (800, 100));
+        assert_eq!(image::load_from_memory(&result.data).unwrap().dimensions(), (800, 100));
+    }
+
+    #[tokio::test]
+    async fn test_compress_larger_output_returns_original_buffer() {
+        // A heavily compressed JPEG only grows when re-encoded at l=40
+        let mut seed: u32 = 5;
+        let noisy = DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 200, |_, _| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let v = (seed >> 16) as u8;
+            image::Rgb([v, v, v])
+        }));
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 5)
+            .encode_image(&noisy)
+            .unwrap();
+        let source = Bytes::from(jpeg);
+
+        let result = compress(&source, &params_for(&source, false), &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.format, None);
+        assert_eq!(result.bypass_reason, Some("larger-after-compression"));
+        assert_eq!(result.data, source);
+        assert_eq!(result.data.as_ptr(), source.as_ptr());
+    }
+
+    #[test]
+    fn test_compute_blurhash() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(800, 600, |x, y| {
+            image::Rgb([(x / 4) as u8, (y / 3) as u8, 90])
+        }));
+        const BASE83: &str =
+            "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+        let hash = compute_blurhash(&img).unwrap();
+        // 1 size flag + 1 max AC + 4 DC + 2 per AC component (4x3 - 1)
+        assert_eq!(hash.len(), 28);
+        assert!(hash.chars().all(|c| BASE83.contains(c)));
+        assert_eq!(compute_blurhash(&img), Some(hash));
+
+        let portrait = img.rotate90();
+        assert_eq!(compute_blurhash(&portrait).unwrap().len(), 28);
+    }
+
+    #[tokio::test]
+    async fn test_compress_blurhash_can_be_disabled() {
+        let png = encode_fixture(
+            &DynamicImage::ImageRgb8(image::RgbImage::from_fn(1000, 600, |x, y| {
+                image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+            })),
+            ImageFormat::Png,
+        );
+        let source = Bytes::from(png);
+
+        let result = compress(&source, &params_for(&source, false), &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+        assert!(result.blurhash.is_some());
+
+        let config = Config { blurhash_enabled: false, ..Config::default() };
+        let result = compress(&source, &params_for(&source, false), &config, &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+        assert!(result.blurhash.is_none());
+    }
+
+    fn animated_gif_fixture(width: u32, height: u32, frames: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut data);
+            for i in 0..frames {
+                let frame = image::RgbaImage::from_fn(width, height, |x, y| {
+                    let n = (x.wrapping_mul(7919) ^ y.wrapping_mul(104_729)).wrapping_add(i as u32 * 31);
+                    image::Rgba([(n % 256) as u8, (x % 256) as u8, (y % 256) as u8, 255])
+                });
+                encoder.encode_frame(image::Frame::new(frame)).unwrap();
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_is_animated_gif() {
+        assert!(is_animated_gif(&animated_gif_fixture(16, 16, 3)));
+        assert!(!is_animated_gif(&animated_gif_fixture(16, 16, 1)));
+        assert_eq!(count_gif_frames(&animated_gif_fixture(16, 16, 3), 10), Some(3));
+
+        let truncated = animated_gif_fixture(16, 16, 2);
+        assert!(!is_animated_gif(&truncated[..truncated.len() / 3]));
+        assert!(!is_animated_gif(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[tokio::test]
+    async fn test_compress_still_gif_drops_animation() {
+        let source = Bytes::from(animated_gif_fixture(1000, 500, 2));
+
+        let params = CompressParams { still: true, ..params_for(&source, false) };
+        let result = compress(&source, &params, &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert!(result.animation_dropped);
+        assert_eq!(result.bypass_reason, None);
+        assert_eq!((result.output_width, result.output_height), (800, 400));
+
+        // Without the flag nothing is reported as dropped
+        let result = compress(&source, &params_for(&source, false), &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+        assert!(!result.animation_dropped);
+    }
+
+    #[tokio::test]
+    async fn test_compress_dual_encode_keeps_smaller_output() {
+        let source = Bytes::from(encode_fixture(
+            &DynamicImage::ImageRgb8(image::RgbImage::from_fn(1000, 600, |x, y| {
+                image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x * y) % 256) as u8])
+            })),
+            ImageFormat::Png,
+        ));
+        let config = Config { blurhash_enabled: false, ..Config::default() };
+
+        let single = compress(&source, &params_for(&source, true), &config, &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+        let params = CompressParams { dual_encode: true, ..params_for(&source, true) };
+        let dual = compress(&source, &params, &config, &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert!(dual.data.len() <= single.data.len());
+        assert!(matches!(dual.format, Some(OutputFormat::Avif | OutputFormat::Jpeg)));
+
+        // Clients asking for JPEG only ever get JPEG
+        let params = CompressParams { dual_encode: true, ..params_for(&source, false) };
+        let jpeg_only = compress(&source, &params, &config, &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+        assert_eq!(jpeg_only.format, Some(OutputFormat::Jpeg));
+    }
+
+    #[cfg(feature = "fast-resize")]
+    #[test]
+    fn test_fast_resize_matches_image_resize() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(1200, 900, |x, y| {
+            image::Rgb([(x / 5) as u8, (y / 4) as u8, ((x + y) / 9) as u8])
+        }));
+
+        let fast = fast_resize(&img, 800, 600).unwrap().to_rgb8();
+        let reference = img.resize_exact(800, 600, image::imageops::FilterType::Lanczos3).to_rgb8();
+
+        let total_diff: u64 = fast
+            .as_raw()
+            .iter()
+            .zip(reference.as_raw())
+            .map(|(a, b)| a.abs_diff(*b) as u64)
+            .sum();
+        let mean_diff = total_diff as f64 / fast.as_raw().len() as f64;
+        assert!(mean_diff < 1.0, "mean channel difference {}", mean_diff);
+    }
+
+    #[tokio::test]
+    async fn test_compress_stops_when_cancelled() {
+        let source = Bytes::from(encode_fixture(
+            &DynamicImage::ImageRgb8(image::RgbImage::new(1000, 600)),
+            ImageFormat::Png,
+        ));
+
+        let result = compress(
+            &source,
+            &params_for(&source, false),
+            &Config::default(),
+            &AtomicBool::new(true),
+            &Logger::default(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(CompressionError::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_compress_lqip_is_tiny() {
+        let mut seed: u32 = 9;
+        let photo = DynamicImage::ImageRgb8(image::RgbImage::from_fn(1600, 1200, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let noise = (seed >> 28) as u8;
+            image::Rgb([(x / 7) as u8 + noise, (y / 5) as u8, 120 + noise])
+        }));
+        let source = Bytes::from(encode_fixture(&photo, ImageFormat::Jpeg));
+
+        let params = CompressParams { lqip: true, quality: 90, width: Some(1000), ..params_for(&source, true) };
+        let result = compress(&source, &params, &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.format, Some(OutputFormat::Jpeg));
+        assert_eq!((result.output_width, result.output_height), (32, 24));
+        assert!(result.data.len() < 2048, "placeholder is {} bytes", result.data.len());
+        assert!(result.blurhash.is_none());
+    }
+
+    #[test]
+    fn test_adjust_levels() {
+        let gray = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, image::Rgb([128, 128, 128])));
+        let pixel = |img: &DynamicImage| img.to_rgb8().get_pixel(0, 0).0[0];
+
+        assert_eq!(pixel(&adjust_levels(gray.clone(), 0, 0)), 128);
+        assert_eq!(pixel(&adjust_levels(gray.clone(), -100, 0)), 0);
+        assert_eq!(pixel(&adjust_levels(gray.clone(), 100, 0)), 255);
+        assert!(pixel(&adjust_levels(gray.clone(), -50, 0)) < 128);
+
+        let mixed = DynamicImage::ImageRgb8(image::RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 { image::Rgb([100, 100, 100]) } else { image::Rgb([160, 160, 160]) }
+        }));
+        let contrasted = adjust_levels(mixed, 0, 50).to_rgb8();
+        assert!(contrasted.get_pixel(0, 0).0[0] < 100);
+        assert!(contrasted.get_pixel(1, 0).0[0] > 160);
+    }
+
+    #[tokio::test]
+    async fn test_compress_invert_turns_white_black() {
+        let white = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(1000, 600, image::Rgb([255, 255, 255])));
+        let source = Bytes::from(encode_fixture(&white, ImageFormat::Bmp));
+
+        for grayscale in [false, true] {
+            let params = CompressParams { invert: true, grayscale, ..params_for(&source, false) };
+            let result = compress(&source, &params, &Config::default(), &AtomicBool::new(false), &Logger::default())
+                .await
+                .unwrap();
+
+            let decoded = image::load_from_memory(&result.data).unwrap().to_luma8();
+            assert!(decoded.pixels().all(|p| p.0[0] < 8), "grayscale={}", grayscale);
+        }
+    }
+
+    #[test]
+    fn test_apply_orientation() {
+        // Red pixel in the top-left corner of a 3x2 image
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(3, 2, |x, y| {
+            if (x, y) == (0, 0) { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 0]) }
+        }));
+        let red_at = |img: &DynamicImage| {
+            let rgb = img.to_rgb8();
+            (rgb.dimensions(), rgb.enumerate_pixels().find(|(_, _, p)| p.0[0] == 255).map(|(x, y, _)| (x, y)))
+        };
+
+        assert_eq!(red_at(&apply_orientation(img.clone(), Rotation::Rotate90, None)), ((2, 3), Some((1, 0))));
+        assert_eq!(red_at(&apply_orientation(img.clone(), Rotation::Rotate180, None)), ((3, 2), Some((2, 1))));
+        assert_eq!(red_at(&apply_orientation(img.clone(), Rotation::Rotate270, None)), ((2, 3), Some((0, 2))));
+        assert_eq!(red_at(&apply_orientation(img.clone(), Rotation::None, Some(Flip::Horizontal))), ((3, 2), Some((2, 0))));
+        // Flip applies after rotation
+        assert_eq!(
+            red_at(&apply_orientation(img, Rotation::Rotate90, Some(Flip::Vertical))),
+            ((2, 3), Some((1, 2))),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compress_rotation_sizes_after_turning() {
+        let source = Bytes::from(encode_fixture(
+            &DynamicImage::ImageRgb8(image::RgbImage::from_fn(1600, 1200, |x, y| {
+                image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x ^ y) % 256) as u8])
+            })),
+            ImageFormat::Jpeg,
+        ));
+
+        let params = CompressParams { rotate: Rotation::Rotate90, ..params_for(&source, false) };
+        let result = compress(&source, &params, &Config::default(), &AtomicBool::new(false), &Logger::default())
+            .await
+            .unwrap();
+
+        assert_eq!((result.original_width, result.original_height), (1600, 1200));
+        assert_eq!((result.output_width, result.output_height), (800, 1067));
+        assert_eq!(image::load_from_memory(&result.data).unwrap().dimensions(), (800, 1067));
+    }
+
+    #[cfg(feature = "quantize")]
+    #[test]
+    fn test_quantize_png_shrinks_colorful_images() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(300, 200, |x, y| {
+            image::Rgba([(x % 256) as u8, (y * 255 / 200) as u8, ((x + y) % 256) as u8, if x < 150 { 255 } else { 128 }])
+        }));
+        let config = Config::default();
+
+        let mut unquantized = Vec::new();
+        img.write_to(&mut Cursor::new(&mut unquantized), ImageFormat::Png).unwrap();
+        let quantized = quantize_png(&img, &config).unwrap();
+        assert!(quantized.len() < unquantized.len());
+
+        let decoded = image::load_from_memory(&quantized).unwrap();
+        assert_eq!(decoded.dimensions(), (300, 200));
+        assert!(decoded.color().has_alpha());
+        let rgba = decoded.to_rgba8();
+        assert!(rgba.get_pixel(10, 10).0[3] > 240);
+        assert!(rgba.get_pixel(250, 10).0[3] < 160);
+
+        // Few-color images are left to the lossless path
+        let flat = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(50, 50, image::Rgba([1, 2, 3, 255])));
+        assert!(quantize_png(&flat, &config).is_none());
+
+        // An impossible quality floor keeps the full colors
+        let strict = Config { png_quantize_min_quality: 101, ..Config::default() };
+        assert!(quantize_png(&img, &strict).is_none());
     }
 }
@@ -0,0 +1,158 @@
+// data_url.rs - Decoding images inlined as data: URLs
+
+use base64::Engine;
+use bytes::Bytes;
+use md5::{Digest, Md5};
+
+/// Why a data: URL can't be used as an image
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DataUrlError {
+    #[error("Malformed data URL: {0}")]
+    Malformed(&'static str),
+    #[error("Data URL payload exceeds {limit} bytes")]
+    TooLarge { limit: u64 },
+    #[error("Data URL media type {0} is not an image")]
+    NotImage(String),
+}
+
+/// A decoded data: URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataUrl {
+    pub media_type: String,
+    pub data: Bytes,
+}
+
+impl DataUrl {
+    /// Short stand-in for the URL, identifying the image by its content
+    pub fn label(&self) -> String {
+        format!("data:{};md5={}", self.media_type, hex::encode(Md5::digest(&self.data)))
+    }
+}
+
+/// Check if `url` is a data: URL
+pub fn is_data_url(url: &str) -> bool {
+    url.trim_start()
+        .get(..5)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("data:"))
+}
+
+/// Decode a `data:[<media type>][;base64],<payload>` URL whose media type is
+/// an image, refusing payloads that decode to more than `limit` bytes
+pub fn decode_data_url(url: &str, limit: u64) -> Result<DataUrl, DataUrlError> {
+    let url = url.trim();
+    if !is_data_url(url) {
+        return Err(DataUrlError::Malformed("missing data: scheme"));
+    }
+    let (header, payload) = url[5..]
+        .split_once(',')
+        .ok_or(DataUrlError::Malformed("missing ','"))?;
+
+    let mut params = header.split(';').map(str::trim);
+    let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+    let is_base64 = params.any(|p| p.eq_ignore_ascii_case("base64"));
+    if !media_type.starts_with("image/") {
+        let media_type = if media_type.is_empty() { "text/plain".to_string() } else { media_type };
+        return Err(DataUrlError::NotImage(media_type));
+    }
+
+    // Either encoding takes at least one payload byte per decoded byte
+    // (base64 takes 4 per 3), so long payloads can be refused undecoded
+    let max_payload = if is_base64 { limit.saturating_mul(4) / 3 + 4 } else { limit.saturating_mul(3) };
+    if payload.len() as u64 > max_payload {
+        return Err(DataUrlError::TooLarge { limit });
+    }
+
+    let data = percent_decode(payload.as_bytes());
+    let data = if is_base64 {
+        // Query strings turn unescaped '+' into spaces, so turn them back
+        let cleaned: Vec<u8> = data
+            .into_iter()
+            .filter(|b| !matches!(b, b'\r' | b'\n' | b'\t'))
+            .map(|b| if b == b' ' { b'+' } else { b })
+            .collect();
+        base64::engine::general_purpose::STANDARD
+            .decode(&cleaned)
+            .map_err(|_| DataUrlError::Malformed("invalid base64"))?
+    } else {
+        data
+    };
+    if data.len() as u64 > limit {
+        return Err(DataUrlError::TooLarge { limit });
+    }
+
+    Ok(DataUrl {
+        media_type,
+        data: Bytes::from(data),
+    })
+}
+
+/// Decode `%XX` escapes, leaving invalid ones as they are
+pub fn percent_decode(input: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let escaped = (input[i] == b'%')
+            .then(|| Some((hex(*input.get(i + 1)?)? << 4) | hex(*input.get(i + 2)?)?))
+            .flatten();
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(input[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_data_url() {
+        let decoded = decode_data_url("data:image/png;base64,aGVsbG8=", 1024).unwrap();
+        assert_eq!(decoded.media_type, "image/png");
+        assert_eq!(decoded.data, Bytes::from_static(b"hello"));
+
+        // Percent-encoded payloads, with and without base64
+        let decoded = decode_data_url("DATA:image/svg+xml;charset=utf-8,%3Csvg%2F%3E", 1024).unwrap();
+        assert_eq!(decoded.media_type, "image/svg+xml");
+        assert_eq!(decoded.data, Bytes::from_static(b"<svg/>"));
+        let decoded = decode_data_url("data:image/gif;base64,aGV%2BbG8=", 1024).unwrap();
+        assert_eq!(decoded.data, Bytes::from_static(b"he~lo"));
+
+        // '+' that a query string turned into a space
+        assert_eq!(
+            decode_data_url("data:image/gif;base64,aGV bG8=", 1024).unwrap().data,
+            decode_data_url("data:image/gif;base64,aGV+bG8=", 1024).unwrap().data
+        );
+    }
+
+    #[test]
+    fn test_decode_data_url_errors() {
+        assert_eq!(
+            decode_data_url("data:text/html,<script>", 1024),
+            Err(DataUrlError::NotImage("text/html".to_string()))
+        );
+        assert_eq!(decode_data_url("data:,hello", 1024), Err(DataUrlError::NotImage("text/plain".to_string())));
+        assert!(matches!(decode_data_url("data:image/png;base64", 1024), Err(DataUrlError::Malformed(_))));
+        assert!(matches!(decode_data_url("data:image/png;base64,!!!", 1024), Err(DataUrlError::Malformed(_))));
+        assert_eq!(
+            decode_data_url("data:image/png;base64,aGVsbG8gd29ybGQ=", 8),
+            Err(DataUrlError::TooLarge { limit: 8 })
+        );
+        assert_eq!(decode_data_url(&format!("data:image/png,{}", "a".repeat(100)), 8), Err(DataUrlError::TooLarge { limit: 8 }));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode(b"a%20b%2fc"), b"a b/c");
+        assert_eq!(percent_decode(b"100%"), b"100%");
+        assert_eq!(percent_decode(b"%zz%4"), b"%zz%4");
+    }
+}
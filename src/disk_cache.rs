@@ -0,0 +1,448 @@
+// disk_cache.rs - Size-bounded cache of compressed responses on disk
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::etag::Validators;
+use crate::response_cache::is_stale;
+
+const BODY_EXTENSION: &str = "body";
+const META_EXTENSION: &str = "meta";
+const TEMP_EXTENSION: &str = "tmp";
+
+/// What is kept next to each body
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Sidecar {
+    content_type: String,
+    /// Seconds since the Unix epoch
+    created_at: u64,
+    /// Size of the image before compression
+    original_size: u64,
+    /// Body length, so a truncated body is never served
+    size: u64,
+    headers: Vec<(String, String)>,
+    /// Upstream validators the body was made from
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+    /// Seconds since the Unix epoch when the upstream last vouched for the
+    /// body. Entries written before it was kept count as stale
+    #[serde(default)]
+    validated_at: u64,
+}
+
+/// A cached response
+#[derive(Debug, Clone)]
+pub struct DiskEntry {
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub validators: Validators,
+    pub validated_at: SystemTime,
+    /// Older than the TTL, so the upstream should confirm it before it is
+    /// served again
+    pub stale: bool,
+}
+
+/// Responses stored as `<key>.body` with a `<key>.meta` sidecar. Files are
+/// written under a temporary name and renamed into place, the sidecar last,
+/// so an entry only exists once both are complete
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Age at which entries go stale, unset when they never do
+    ttl: Option<Duration>,
+    index: Mutex<DiskIndex>,
+    /// Makes temporary file names unique across concurrent writes
+    next_temp: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct DiskIndex {
+    /// Size and last use of each entry
+    entries: HashMap<String, (u64, u64)>,
+    /// Keys by last use, oldest first
+    order: BTreeMap<u64, String>,
+    next_use: u64,
+    bytes: u64,
+}
+
+impl DiskIndex {
+    fn touch(&mut self, key: &str) {
+        let use_id = self.next_use;
+        if let Some((_, last_use)) = self.entries.get_mut(key) {
+            self.order.remove(last_use);
+            *last_use = use_id;
+            self.order.insert(use_id, key.to_string());
+            self.next_use += 1;
+        }
+    }
+
+    fn insert(&mut self, key: &str, size: u64) {
+        self.remove(key);
+        let use_id = self.next_use;
+        self.next_use += 1;
+        self.entries.insert(key.to_string(), (size, use_id));
+        self.order.insert(use_id, key.to_string());
+        self.bytes += size;
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        let Some((size, last_use)) = self.entries.remove(key) else {
+            return false;
+        };
+        self.order.remove(&last_use);
+        self.bytes -= size;
+        true
+    }
+}
+
+impl DiskCache {
+    /// Open the cache in `dir`, creating it if needed. The index is rebuilt
+    /// from the sidecars found there, oldest first; incomplete or corrupt
+    /// entries and leftover temporary files are deleted
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut found = Vec::new();
+        for file in std::fs::read_dir(&dir)? {
+            let path = file?.path();
+            let extension = path.extension().and_then(|e| e.to_str());
+            if extension == Some(TEMP_EXTENSION) {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let Some(key) = path.file_stem().and_then(|s| s.to_str()).filter(|_| extension == Some(BODY_EXTENSION))
+            else {
+                continue;
+            };
+            let sidecar = std::fs::read(dir.join(format!("{}.{}", key, META_EXTENSION)))
+                .ok()
+                .and_then(|meta| serde_json::from_slice::<Sidecar>(&meta).ok());
+            let body_size = std::fs::metadata(&path).map(|m| m.len()).ok();
+            match sidecar {
+                Some(sidecar) if body_size == Some(sidecar.size) => found.push((sidecar.created_at, key.to_string(), sidecar.size)),
+                _ => remove_files(&dir, key),
+            }
+        }
+        // Sidecars without a body
+        for file in std::fs::read_dir(&dir)? {
+            let path = file?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(META_EXTENSION)
+                && !path.with_extension(BODY_EXTENSION).exists()
+            {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+
+        found.sort();
+        let mut index = DiskIndex::default();
+        for (_, key, size) in found {
+            index.insert(&key, size);
+        }
+        let cache = DiskCache {
+            dir,
+            max_bytes,
+            ttl: None,
+            index: Mutex::new(index),
+            next_temp: AtomicU64::new(0),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    /// Entries go stale `ttl` after they were last validated. A TTL of 0
+    /// keeps them fresh until evicted
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = (!ttl.is_zero()).then_some(ttl);
+        self
+    }
+
+    /// The response cached for `key`, which becomes the most recently used
+    pub async fn get(&self, key: &str) -> Option<DiskEntry> {
+        if !self.index.lock().unwrap().entries.contains_key(key) {
+            return None;
+        }
+        match self.read(key).await {
+            Some(entry) => {
+                self.index.lock().unwrap().touch(key);
+                Some(entry)
+            }
+            None => {
+                // Damaged or deleted behind our back
+                self.index.lock().unwrap().remove(key);
+                remove_files(&self.dir, key);
+                None
+            }
+        }
+    }
+
+    async fn read(&self, key: &str) -> Option<DiskEntry> {
+        let sidecar = self.read_sidecar(key).await?;
+        let body = tokio::fs::read(self.path(key, BODY_EXTENSION)).await.ok()?;
+        if body.len() as u64 != sidecar.size {
+            return None;
+        }
+        let mut headers = HeaderMap::new();
+        for (name, value) in &sidecar.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            headers.append(name, HeaderValue::from_str(value).ok()?);
+        }
+        let validated_at = UNIX_EPOCH + Duration::from_secs(sidecar.validated_at);
+        Some(DiskEntry {
+            headers,
+            body: Bytes::from(body),
+            validators: Validators {
+                etag: sidecar.etag,
+                last_modified: sidecar.last_modified,
+            },
+            validated_at,
+            stale: is_stale(validated_at, self.ttl),
+        })
+    }
+
+    async fn read_sidecar(&self, key: &str) -> Option<Sidecar> {
+        let meta = tokio::fs::read(self.path(key, META_EXTENSION)).await.ok()?;
+        serde_json::from_slice(&meta).ok()
+    }
+
+    /// Start the TTL of `key` over, once the upstream confirmed it unchanged
+    pub async fn refresh(&self, key: &str) -> io::Result<()> {
+        if !self.index.lock().unwrap().entries.contains_key(key) {
+            return Ok(());
+        }
+        let Some(mut sidecar) = self.read_sidecar(key).await else {
+            return Ok(());
+        };
+        sidecar.validated_at = unix_now();
+        let meta = serde_json::to_vec(&sidecar).map_err(io::Error::other)?;
+        self.write_atomically(&self.path(key, META_EXTENSION), &meta).await
+    }
+
+    /// Cache a response along with the upstream validators it was made from,
+    /// evicting the least recently used ones over the budget. Bodies larger
+    /// than the whole budget aren't cached
+    pub async fn insert(
+        &self,
+        key: &str,
+        headers: &HeaderMap,
+        body: &Bytes,
+        original_size: u64,
+        validators: &Validators,
+    ) -> io::Result<()> {
+        let size = body.len() as u64;
+        if size > self.max_bytes {
+            return Ok(());
+        }
+        let sidecar = Sidecar {
+            content_type: headers
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+            created_at: unix_now(),
+            original_size,
+            size,
+            headers: headers
+                .iter()
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                .collect(),
+            etag: validators.etag.clone(),
+            last_modified: validators.last_modified.clone(),
+            validated_at: unix_now(),
+        };
+        let meta = serde_json::to_vec(&sidecar).map_err(io::Error::other)?;
+
+        self.write_atomically(&self.path(key, BODY_EXTENSION), body).await?;
+        self.write_atomically(&self.path(key, META_EXTENSION), &meta).await?;
+        self.index.lock().unwrap().insert(key, size);
+        self.evict();
+        Ok(())
+    }
+
+    async fn write_atomically(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let temp = self.dir.join(format!(
+            "{}.{}.{}",
+            path.file_name().and_then(|n| n.to_str()).unwrap_or_default(),
+            self.next_temp.fetch_add(1, Ordering::Relaxed),
+            TEMP_EXTENSION,
+        ));
+        let written = async {
+            tokio::fs::write(&temp, data).await?;
+            tokio::fs::rename(&temp, path).await
+        }
+        .await;
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&temp).await;
+        }
+        written
+    }
+
+    fn evict(&self) {
+        let mut index = self.index.lock().unwrap();
+        while index.bytes > self.max_bytes {
+            let Some((_, oldest)) = index.order.pop_first() else {
+                break;
+            };
+            if let Some((size, _)) = index.entries.remove(&oldest) {
+                index.bytes -= size;
+            }
+            remove_files(&self.dir, &oldest);
+        }
+    }
+
+    fn path(&self, key: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, extension))
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().entries.len()
+    }
+
+    /// Bytes of bodies on disk
+    pub fn bytes(&self) -> u64 {
+        self.index.lock().unwrap().bytes
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Delete an entry's files, sidecar first so it never outlives its body
+/// as a valid entry
+fn remove_files(dir: &Path, key: &str) {
+    let _ = std::fs::remove_file(dir.join(format!("{}.{}", key, META_EXTENSION)));
+    let _ = std::fs::remove_file(dir.join(format!("{}.{}", key, BODY_EXTENSION)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bandwidth-hero-disk-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn image_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("image/avif"));
+        headers.insert("x-bytes-saved", HeaderValue::from_static("900"));
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_eviction_is_least_recently_used_first() {
+        let dir = test_dir("eviction");
+        let cache = DiskCache::open(&dir, 100).unwrap();
+        let body = |len: usize| Bytes::from(vec![7u8; len]);
+        cache.insert("a", &image_headers(), &body(40), 1000, &Validators::default()).await.unwrap();
+        cache.insert("b", &image_headers(), &body(40), 1000, &Validators::default()).await.unwrap();
+        assert!(cache.get("a").await.is_some());
+
+        cache.insert("c", &image_headers(), &body(30), 1000, &Validators::default()).await.unwrap();
+        assert!(cache.get("b").await.is_none());
+        assert!(!dir.join("b.body").exists() && !dir.join("b.meta").exists());
+        let entry = cache.get("a").await.unwrap();
+        assert_eq!(entry.body, body(40));
+        assert_eq!(entry.headers["content-type"], "image/avif");
+        assert_eq!(cache.bytes(), 70);
+
+        cache.insert("d", &image_headers(), &body(101), 1000, &Validators::default()).await.unwrap();
+        assert!(cache.get("d").await.is_none());
+        assert_eq!(cache.len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_entries_are_dropped() {
+        let dir = test_dir("corrupt");
+        let cache = DiskCache::open(&dir, 1000).unwrap();
+        for key in ["a", "b", "c"] {
+            cache.insert(key, &image_headers(), &Bytes::from_static(b"image"), 10, &Validators::default()).await.unwrap();
+        }
+        std::fs::write(dir.join("a.meta"), b"{not json").unwrap();
+        std::fs::write(dir.join("b.body"), b"ima").unwrap();
+
+        // Found when read
+        assert!(cache.get("a").await.is_none());
+        assert!(cache.get("b").await.is_none());
+        assert!(!dir.join("a.body").exists() && !dir.join("b.meta").exists());
+        assert_eq!(cache.len(), 1);
+
+        // Found when the directory is scanned
+        std::fs::write(dir.join("c.meta"), b"").unwrap();
+        std::fs::write(dir.join("d.meta"), b"{}").unwrap();
+        let reopened = DiskCache::open(&dir, 1000).unwrap();
+        assert_eq!(reopened.len(), 0);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_entries_survive_a_restart() {
+        let dir = test_dir("restart");
+        let cache = DiskCache::open(&dir, 1000).unwrap();
+        cache.insert("a", &image_headers(), &Bytes::from_static(b"first"), 10, &Validators::default()).await.unwrap();
+        cache.insert("b", &image_headers(), &Bytes::from_static(b"second"), 10, &Validators::default()).await.unwrap();
+        drop(cache);
+        // A write cut short by a crash
+        std::fs::write(dir.join("c.body.0.tmp"), b"half").unwrap();
+
+        let cache = DiskCache::open(&dir, 1000).unwrap();
+        assert_eq!((cache.len(), cache.bytes()), (2, 11));
+        assert!(!dir.join("c.body.0.tmp").exists());
+        let entry = cache.get("b").await.unwrap();
+        assert_eq!(entry.body, Bytes::from_static(b"second"));
+        assert_eq!(entry.headers["x-bytes-saved"], "900");
+
+        // Reopening with a smaller budget drops the oldest
+        drop(cache);
+        let cache = DiskCache::open(&dir, 8).unwrap();
+        assert!(cache.get("a").await.is_none());
+        assert!(cache.get("b").await.is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_validators_and_staleness() {
+        let dir = test_dir("stale");
+        let validators = Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        };
+        let cache = DiskCache::open(&dir, 1000).unwrap().with_ttl(Duration::from_secs(60));
+        cache.insert("a", &image_headers(), &Bytes::from_static(b"image"), 10, &validators).await.unwrap();
+        let entry = cache.get("a").await.unwrap();
+        assert_eq!(entry.validators, validators);
+        assert!(!entry.stale);
+
+        // Validated long ago, as an entry written before validation times were kept
+        let meta = std::fs::read_to_string(dir.join("a.meta")).unwrap();
+        let mut sidecar: serde_json::Value = serde_json::from_str(&meta).unwrap();
+        sidecar.as_object_mut().unwrap().remove("validatedAt");
+        std::fs::write(dir.join("a.meta"), sidecar.to_string()).unwrap();
+        assert!(cache.get("a").await.unwrap().stale);
+
+        cache.refresh("a").await.unwrap();
+        let entry = cache.get("a").await.unwrap();
+        assert!(!entry.stale);
+        assert_eq!(entry.validators, validators);
+        assert_eq!(entry.body, Bytes::from_static(b"image"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+// disposition.rs - Content-Disposition filenames for served images
+
+use axum::http::HeaderValue;
+use url::Url;
+
+use crate::data_url::percent_decode;
+
+/// File names are cut to this many bytes before the extension, well under
+/// the 255 most filesystems allow
+const MAX_STEM_BYTES: usize = 120;
+
+/// `inline` disposition naming the image after the last path segment of
+/// `url`, where it was served from, with the extension of `content_type`.
+/// Images without a name in their URL, e.g. uploads, are named `url_hash`
+pub fn content_disposition(url: &str, content_type: &str, url_hash: &str) -> HeaderValue {
+    let filename = derive_filename(url, content_type, url_hash);
+    let fallback: String = filename.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect();
+    let value = if fallback == filename {
+        format!("inline; filename=\"{}\"", filename)
+    } else {
+        // RFC 6266: older clients take the ASCII name, others the UTF-8 one
+        format!("inline; filename=\"{}\"; filename*=UTF-8''{}", fallback, rfc5987_encode(&filename))
+    };
+    HeaderValue::from_str(&value).unwrap_or_else(|_| HeaderValue::from_static("inline"))
+}
+
+/// File name for an image of `content_type` served from `url`
+pub fn derive_filename(url: &str, content_type: &str, url_hash: &str) -> String {
+    let name = url_filename(url);
+    let (stem, extension) = match (name.as_deref(), extension_for(content_type)) {
+        // The URL's own extension may not match what is served
+        (Some(name), Some(extension)) => {
+            let stem = name.rsplit_once('.').filter(|(stem, _)| !stem.is_empty()).map_or(name, |(stem, _)| stem);
+            (stem, Some(extension))
+        }
+        (Some(name), None) => (name, None),
+        (None, extension) => (url_hash, extension),
+    };
+    let stem = sanitize(stem);
+    let stem = if stem.is_empty() { url_hash } else { truncate(&stem, MAX_STEM_BYTES) };
+    match extension {
+        Some(extension) => format!("{}.{}", stem, extension),
+        None => stem.to_string(),
+    }
+}
+
+/// The decoded last path segment of an http(s) URL, if it has one
+fn url_filename(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok().filter(|url| matches!(url.scheme(), "http" | "https"))?;
+    let segment = url.path_segments()?.next_back().filter(|segment| !segment.is_empty())?;
+    Some(String::from_utf8_lossy(&percent_decode(segment.as_bytes())).into_owned())
+}
+
+/// File extension for an image media type
+fn extension_for(content_type: &str) -> Option<&'static str> {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    Some(match essence.as_str() {
+        "image/avif" => "avif",
+        "image/jpeg" | "image/jpg" | "image/pjpeg" => "jpg",
+        "image/png" | "image/apng" => "png",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        "image/jxl" => "jxl",
+        "image/svg+xml" => "svg",
+        "image/bmp" | "image/x-ms-bmp" => "bmp",
+        "image/tiff" => "tiff",
+        "image/heic" => "heic",
+        "image/heif" => "heif",
+        "image/x-icon" | "image/vnd.microsoft.icon" => "ico",
+        _ => return None,
+    })
+}
+
+/// Replace characters that are unsafe in a header or a file name, and
+/// trim the dots and spaces some filesystems drop
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | '/' | ':' | '*' | '?' | '<' | '>' | '|' | ';' | '%' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    name.trim_matches(|c: char| c == '.' || c.is_whitespace()).to_string()
+}
+
+/// At most `max_bytes` of `name`, cut on a character boundary
+fn truncate(name: &str, max_bytes: usize) -> &str {
+    if name.len() <= max_bytes {
+        return name;
+    }
+    let end = (0..=max_bytes).rev().find(|&i| name.is_char_boundary(i)).unwrap_or(0);
+    &name[..end]
+}
+
+/// Percent-encode everything but RFC 5987 `attr-char`s
+fn rfc5987_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (b as char).to_string(),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef";
+
+    #[test]
+    fn test_derive_filename() {
+        let cases = [
+            ("https://cdn.example.com/photos/cat.png", "image/avif", "cat.avif"),
+            ("https://cdn.example.com/photos/cat.png?w=200#top", "image/jpeg", "cat.jpg"),
+            ("https://cdn.example.com/photos/cat.tar.gz.png", "image/webp", "cat.tar.gz.webp"),
+            // No extension to replace
+            ("https://cdn.example.com/image", "image/jpeg; charset=binary", "image.jpg"),
+            ("https://cdn.example.com/.hidden", "image/png", "hidden.png"),
+            // Percent-escapes are decoded, unsafe characters replaced
+            ("https://cdn.example.com/my%20cat%22s%3Aphoto.jpg", "image/avif", "my cat_s_photo.avif"),
+            ("https://cdn.example.com/a%2Fb%5Cc.jpg", "image/avif", "a_b_c.avif"),
+            // Unknown types keep the name as it is
+            ("https://cdn.example.com/cat.png", "application/octet-stream", "cat.png"),
+            // Nothing to name it by
+            ("https://cdn.example.com/", "image/avif", "0123456789abcdef.avif"),
+            ("https://cdn.example.com/photos/", "image/avif", "0123456789abcdef.avif"),
+            ("https://cdn.example.com/...", "image/avif", "0123456789abcdef.avif"),
+            ("data:image/png;md5=abc", "image/jpeg", "0123456789abcdef.jpg"),
+            ("not a url", "image/jpeg", "0123456789abcdef.jpg"),
+        ];
+        for (url, content_type, expected) in cases {
+            assert_eq!(derive_filename(url, content_type, HASH), expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_long_names_are_truncated() {
+        let url = format!("https://cdn.example.com/{}.jpg", "a".repeat(300));
+        assert_eq!(derive_filename(&url, "image/avif", HASH), format!("{}.avif", "a".repeat(MAX_STEM_BYTES)));
+
+        // Never in the middle of a character
+        let url = format!("https://cdn.example.com/{}.jpg", "猫".repeat(100));
+        let filename = derive_filename(&url, "image/avif", HASH);
+        assert_eq!(filename, format!("{}.avif", "猫".repeat(MAX_STEM_BYTES / 3)));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("https://cdn.example.com/cat.png", "image/avif", HASH),
+            "inline; filename=\"cat.avif\""
+        );
+        assert_eq!(
+            content_disposition("https://cdn.example.com/", "image/jpeg", HASH),
+            "inline; filename=\"0123456789abcdef.jpg\""
+        );
+        // Non-ASCII names get an ASCII fallback and an RFC 5987 UTF-8 name
+        assert_eq!(
+            content_disposition("https://cdn.example.com/%E7%8C%AB%20caf%C3%A9.png", "image/avif", HASH),
+            "inline; filename=\"_ caf_.avif\"; filename*=UTF-8''%E7%8C%AB%20caf%C3%A9.avif"
+        );
+        assert_eq!(
+            content_disposition("https://cdn.example.com/%E7%8C%AB.png", "image/avif", HASH),
+            "inline; filename=\"_.avif\"; filename*=UTF-8''%E7%8C%AB.avif"
+        );
+    }
+}
@@ -0,0 +1,345 @@
+// dns.rs - Cached DNS resolution for upstream hosts
+
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use futures_util::future::BoxFuture;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use tokio::time::Instant;
+
+/// Looks up the addresses of a host name
+pub trait Lookup: Send + Sync + 'static {
+    fn lookup(&self, host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>>;
+}
+
+/// The system resolver
+#[derive(Debug, Default)]
+pub struct SystemLookup;
+
+impl Lookup for SystemLookup {
+    fn lookup(&self, host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host.as_str(), 0)).await?.collect()) })
+    }
+}
+
+/// Which address families upstream connections use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpPreference {
+    /// Addresses in the order they were resolved
+    #[default]
+    Auto,
+    Ipv4Only,
+    Ipv6Only,
+    /// IPv4 addresses first, with IPv6 as the happy-eyeballs fallback
+    Ipv4First,
+}
+
+impl IpPreference {
+    /// Filter and order resolved addresses. The connector tries the family
+    /// of the first address, racing the other family after a short delay
+    pub fn apply(self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        match self {
+            IpPreference::Auto => addrs,
+            IpPreference::Ipv4Only => addrs.into_iter().filter(SocketAddr::is_ipv4).collect(),
+            IpPreference::Ipv6Only => addrs.into_iter().filter(SocketAddr::is_ipv6).collect(),
+            IpPreference::Ipv4First => {
+                let (mut v4, v6): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv4);
+                v4.extend(v6);
+                v4
+            }
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IpPreference::Auto => "auto",
+            IpPreference::Ipv4Only => "ipv4-only",
+            IpPreference::Ipv6Only => "ipv6-only",
+            IpPreference::Ipv4First => "ipv4-first",
+        }
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(IpPreference::Auto),
+            "ipv4-only" => Ok(IpPreference::Ipv4Only),
+            "ipv6-only" => Ok(IpPreference::Ipv6Only),
+            "ipv4-first" => Ok(IpPreference::Ipv4First),
+            _ => Err(format!("Unknown IP preference: {}", value)),
+        }
+    }
+}
+
+/// A failed lookup, kept recognizable in reqwest's error chain
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to resolve {host}: {source}")]
+pub struct DnsError {
+    pub host: String,
+    #[source]
+    pub source: io::Error,
+}
+
+/// Cached addresses for one host
+#[derive(Debug)]
+struct Entry {
+    addrs: Vec<SocketAddr>,
+    resolved: Instant,
+    refreshing: bool,
+}
+
+/// Resolution cache. Answers are fresh for `ttl`, then served for another
+/// `ttl` while a background lookup refreshes them
+pub struct DnsCache {
+    lookup: Arc<dyn Lookup>,
+    ttl: Duration,
+    capacity: usize,
+    preference: IpPreference,
+    entries: DashMap<String, Entry>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        DnsCache::new(Arc::new(SystemLookup), Duration::from_secs(60), 1024)
+    }
+}
+
+impl DnsCache {
+    pub fn new(lookup: Arc<dyn Lookup>, ttl: Duration, capacity: usize) -> Self {
+        DnsCache {
+            lookup,
+            ttl,
+            capacity,
+            preference: IpPreference::Auto,
+            entries: DashMap::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Order and filter answers by `preference`
+    pub fn with_ip_preference(mut self, preference: IpPreference) -> Self {
+        self.preference = preference;
+        self
+    }
+
+    /// Resolve `host`, from the cache when possible, in the order of the
+    /// IP preference
+    pub async fn resolve(self: &Arc<Self>, host: &str) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.resolve_cached(host).await?;
+        let addrs = self.preference.apply(addrs);
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no {} address", host, self.preference.as_str()),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    async fn resolve_cached(self: &Arc<Self>, host: &str) -> io::Result<Vec<SocketAddr>> {
+        let host = host.to_ascii_lowercase();
+
+        if let Some(mut entry) = self.entries.get_mut(&host) {
+            let age = entry.resolved.elapsed();
+            if age < self.ttl.saturating_mul(2) {
+                if age >= self.ttl && !entry.refreshing {
+                    entry.refreshing = true;
+                    self.refresh(host.clone());
+                }
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(entry.addrs.clone());
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let addrs = self.lookup.lookup(host.clone()).await?;
+        self.insert(host, addrs.clone());
+        Ok(addrs)
+    }
+
+    /// Look `host` up again in the background
+    fn refresh(self: &Arc<Self>, host: String) {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            match cache.lookup.lookup(host.clone()).await {
+                Ok(addrs) => cache.insert(host, addrs),
+                // Keep serving the stale answer until it runs out
+                Err(_) => {
+                    if let Some(mut entry) = cache.entries.get_mut(&host) {
+                        entry.refreshing = false;
+                    }
+                }
+            }
+        });
+    }
+
+    fn insert(&self, host: String, addrs: Vec<SocketAddr>) {
+        // Failed resolutions are never cached
+        if addrs.is_empty() || self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&host) {
+            // Drop what can no longer be served, then the oldest if still full
+            let max_age = self.ttl.saturating_mul(2);
+            self.entries.retain(|_, entry| entry.resolved.elapsed() < max_age);
+            if self.entries.len() >= self.capacity {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|entry| entry.resolved)
+                    .map(|entry| entry.key().clone());
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+        self.entries.insert(
+            host,
+            Entry {
+                addrs,
+                resolved: Instant::now(),
+                refreshing: false,
+            },
+        );
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Number of hosts currently cached
+    pub fn host_count(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// reqwest resolver backed by a `DnsCache`
+pub struct CachingResolver(pub Arc<DnsCache>);
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let cache = self.0.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs = cache.resolve(host).await.map_err(|source| DnsError {
+                host: host.to_string(),
+                source,
+            })?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Resolves every host to 10.0.0.<lookups so far>, counting lookups
+    #[derive(Default)]
+    struct CountingLookup {
+        lookups: AtomicUsize,
+    }
+
+    impl Lookup for CountingLookup {
+        fn lookup(&self, _host: String) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+            let n = self.lookups.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::pin(async move { Ok(vec![SocketAddr::from(([10, 0, 0, n as u8], 0))]) })
+        }
+    }
+
+    fn counting_cache(ttl_secs: u64, capacity: usize) -> (Arc<DnsCache>, Arc<CountingLookup>) {
+        let lookup = Arc::new(CountingLookup::default());
+        let cache = DnsCache::new(lookup.clone(), Duration::from_secs(ttl_secs), capacity);
+        (Arc::new(cache), lookup)
+    }
+
+    fn last_octet(addrs: &[SocketAddr]) -> u8 {
+        match addrs[0].ip() {
+            std::net::IpAddr::V4(ip) => ip.octets()[3],
+            std::net::IpAddr::V6(_) => unreachable!(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_answers_are_cached_until_they_expire() {
+        let (cache, lookup) = counting_cache(60, 16);
+
+        assert_eq!(last_octet(&cache.resolve("cdn.example.com").await.unwrap()), 1);
+        assert_eq!(last_octet(&cache.resolve("CDN.example.com").await.unwrap()), 1);
+        assert_eq!(lookup.lookups.load(Ordering::SeqCst), 1);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // Stale answers are served while a refresh runs
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(last_octet(&cache.resolve("cdn.example.com").await.unwrap()), 1);
+        tokio::task::yield_now().await;
+        assert_eq!(lookup.lookups.load(Ordering::SeqCst), 2);
+        assert_eq!(last_octet(&cache.resolve("cdn.example.com").await.unwrap()), 2);
+
+        // Past twice the TTL the answer is looked up again before use
+        tokio::time::advance(Duration::from_secs(121)).await;
+        assert_eq!(last_octet(&cache.resolve("cdn.example.com").await.unwrap()), 3);
+        assert_eq!((cache.hits(), cache.misses()), (3, 2));
+    }
+
+    #[test]
+    fn test_ip_preference_ordering() {
+        let v4 = |n| SocketAddr::from(([192, 0, 2, n], 443));
+        let v6 = |n| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, n], 443));
+        let resolved = vec![v6(1), v4(1), v6(2), v4(2)];
+
+        assert_eq!(IpPreference::Auto.apply(resolved.clone()), resolved);
+        assert_eq!(IpPreference::Ipv4Only.apply(resolved.clone()), vec![v4(1), v4(2)]);
+        assert_eq!(IpPreference::Ipv6Only.apply(resolved.clone()), vec![v6(1), v6(2)]);
+        assert_eq!(IpPreference::Ipv4First.apply(resolved.clone()), vec![v4(1), v4(2), v6(1), v6(2)]);
+        assert_eq!(IpPreference::Ipv4First.apply(vec![v6(1)]), vec![v6(1)]);
+
+        assert_eq!("IPv4-First".parse(), Ok(IpPreference::Ipv4First));
+        assert!("ipv4".parse::<IpPreference>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ip_preference_without_matching_addresses() {
+        // CountingLookup only returns IPv4 addresses
+        let (cache, _) = counting_cache(60, 16);
+        let cache = Arc::new(Arc::into_inner(cache).unwrap().with_ip_preference(IpPreference::Ipv6Only));
+        assert_eq!(cache.resolve("v4only.example.com").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cache_is_capped() {
+        let (cache, lookup) = counting_cache(60, 2);
+        cache.resolve("a.example.com").await.unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        cache.resolve("b.example.com").await.unwrap();
+        cache.resolve("c.example.com").await.unwrap();
+        assert_eq!(cache.host_count(), 2);
+
+        // The oldest entry made room
+        cache.resolve("b.example.com").await.unwrap();
+        assert_eq!(lookup.lookups.load(Ordering::SeqCst), 3);
+        cache.resolve("a.example.com").await.unwrap();
+        assert_eq!(lookup.lookups.load(Ordering::SeqCst), 4);
+
+        let (uncached, lookup) = counting_cache(60, 0);
+        uncached.resolve("a.example.com").await.unwrap();
+        uncached.resolve("a.example.com").await.unwrap();
+        assert_eq!(lookup.lookups.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,126 @@
+// etag.rs - ETags for transformed images and their upstream counterparts
+
+use dashmap::DashMap;
+use md5::{Digest, Md5};
+
+/// Mappings kept before the map is cleared. A forgotten ETag only costs
+/// one full fetch
+const MAX_ENTRIES: usize = 10_000;
+
+/// ETag for a transformed image. It covers the upstream ETag and the
+/// request parameters, and is weak because dual encoding can pick different
+/// bytes for the same request
+pub fn response_etag(url_hash: &str, params_key: &str, upstream_etag: &str) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(url_hash.as_bytes());
+    hasher.update([0]);
+    hasher.update(params_key.as_bytes());
+    hasher.update([0]);
+    hasher.update(upstream_etag.as_bytes());
+    format!("W/\"{}\"", hex::encode(hasher.finalize()))
+}
+
+/// Strong ETag for a response body, for responses whose upstream sent no
+/// ETag of its own
+pub fn content_etag(body: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(Md5::digest(body)))
+}
+
+/// Whether a client `If-None-Match` lists `etag`. The comparison is weak,
+/// as RFC 9110 asks for `If-None-Match`
+pub fn if_none_match_matches(value: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    value.trim() == "*" || value.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+/// Upstream cache validators
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Whether the upstream sent anything to revalidate with
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// Remembers which upstream ETag each ETag we handed out was derived from,
+/// so client revalidations can be forwarded upstream
+#[derive(Debug, Default)]
+pub struct EtagMap {
+    upstream: DashMap<String, String>,
+}
+
+impl EtagMap {
+    pub fn remember(&self, ours: &str, upstream_etag: &str) {
+        if self.upstream.len() >= MAX_ENTRIES && !self.upstream.contains_key(ours) {
+            self.upstream.clear();
+        }
+        self.upstream.insert(ours.to_string(), upstream_etag.to_string());
+    }
+
+    /// Rewrite a client `If-None-Match` into the upstream ETags it stands
+    /// for. Returns `None` when none of the tags are known
+    pub fn translate_if_none_match(&self, value: &str) -> Option<String> {
+        if value.trim() == "*" {
+            return Some("*".to_string());
+        }
+        let upstream: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter_map(|tag| self.upstream.get(tag).map(|upstream| upstream.clone()))
+            .collect();
+        (!upstream.is_empty()).then(|| upstream.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_etag() {
+        let etag = response_etag("hash", "w=100", "\"v1\"");
+        assert!(etag.starts_with("W/\"") && etag.ends_with('"'));
+        assert_eq!(etag, response_etag("hash", "w=100", "\"v1\""));
+        assert_ne!(etag, response_etag("hash", "w=200", "\"v1\""));
+        assert_ne!(etag, response_etag("hash", "w=100", "\"v2\""));
+        assert_ne!(etag, response_etag("other", "w=100", "\"v1\""));
+    }
+
+    #[test]
+    fn test_content_etag() {
+        let etag = content_etag(b"image");
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(etag, content_etag(b"image"));
+        assert_ne!(etag, content_etag(b"other"));
+    }
+
+    #[test]
+    fn test_if_none_match_matches() {
+        assert!(if_none_match_matches("\"a\"", "\"a\""));
+        assert!(!if_none_match_matches("\"b\"", "\"a\""));
+        assert!(if_none_match_matches("\"b\", W/\"a\",\"c\"", "\"a\""));
+        assert!(if_none_match_matches("\"a\"", "W/\"a\""));
+        assert!(!if_none_match_matches("\"b\", \"c\"", "W/\"a\""));
+        assert!(if_none_match_matches(" * ", "\"a\""));
+    }
+
+    #[test]
+    fn test_translate_if_none_match() {
+        let map = EtagMap::default();
+        map.remember("W/\"ours-1\"", "\"v1\"");
+        map.remember("W/\"ours-2\"", "W/\"v2\"");
+
+        assert_eq!(map.translate_if_none_match("W/\"ours-1\"").as_deref(), Some("\"v1\""));
+        assert_eq!(
+            map.translate_if_none_match("W/\"ours-1\", W/\"unknown\",W/\"ours-2\"").as_deref(),
+            Some("\"v1\", W/\"v2\"")
+        );
+        assert_eq!(map.translate_if_none_match("W/\"unknown\""), None);
+        assert_eq!(map.translate_if_none_match(" * ").as_deref(), Some("*"));
+    }
+}
@@ -0,0 +1,112 @@
+// load.rs - Work in progress right now, for health and readiness checks
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Number of operations of one kind in progress
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicUsize);
+
+impl Gauge {
+    /// Count one more operation until the returned guard is dropped
+    pub fn start(self: &Arc<Self>) -> GaugeGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(self.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts its operation as in progress until dropped, finished or not
+#[derive(Debug)]
+pub struct GaugeGuard(Arc<Gauge>);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How long a semaphore has had no free permits, from regular samples
+#[derive(Debug, Default)]
+pub struct Saturation {
+    since: Mutex<Option<Instant>>,
+}
+
+impl Saturation {
+    /// Record whether the semaphore has no free permits right now. Any
+    /// sample with a free permit starts the count over
+    pub fn sample(&self, saturated: bool) {
+        let mut since = self.since.lock().unwrap();
+        match (saturated, *since) {
+            (false, _) => *since = None,
+            (true, None) => *since = Some(Instant::now()),
+            (true, Some(_)) => {}
+        }
+    }
+
+    /// Time saturated without a break, as of the last sample. `None` when
+    /// it wasn't saturated then
+    pub fn saturated_for(&self) -> Option<Duration> {
+        self.since.lock().unwrap().map(|since| since.elapsed())
+    }
+}
+
+/// What `/ready` decides from
+#[derive(Debug, Default)]
+pub struct Readiness {
+    pub fetch: Saturation,
+    pub compression: Saturation,
+    draining: AtomicBool,
+}
+
+impl Readiness {
+    /// Shutdown began; the server stays unready from now on
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guards_count_until_dropped() {
+        let gauge = Arc::new(Gauge::default());
+        let first = gauge.start();
+        let second = gauge.start();
+        assert_eq!(gauge.count(), 2);
+        drop(first);
+        assert_eq!(gauge.count(), 1);
+        drop(second);
+        assert_eq!(gauge.count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_saturation_counts_from_the_first_saturated_sample() {
+        let saturation = Saturation::default();
+        assert_eq!(saturation.saturated_for(), None);
+
+        saturation.sample(true);
+        tokio::time::advance(Duration::from_secs(5)).await;
+        saturation.sample(true);
+        assert_eq!(saturation.saturated_for(), Some(Duration::from_secs(5)));
+
+        // A free permit in between starts over
+        saturation.sample(false);
+        assert_eq!(saturation.saturated_for(), None);
+        saturation.sample(true);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(saturation.saturated_for(), Some(Duration::from_secs(1)));
+    }
+}
@@ -117,6 +117,7 @@ impl Logger {
         _bytes_saved: Option<u64>,
         quality: u8,
         format: &str,
+        encode_ms: u64,
         error: Option<&str>,
     ) {
         use colors::*;
@@ -144,7 +145,8 @@ impl Logger {
                 + " " + DIM + "→" + RESET 
                 + " " + GREEN + &self.format_bytes(comp_size) + RESET
                 + " " + CYAN + &format!("(-{:.1}%)", percent) + RESET
-                + " " + DIM + &format!("Q:{}", quality) + RESET;
+                + " " + DIM + &format!("Q:{}", quality) + RESET
+                + " " + DIM + &format!("{}ms", encode_ms) + RESET;
             info!("{}", msg);
         }
     }
@@ -188,6 +190,14 @@ impl Logger {
         let reason_badge = match reason {
             "already_small" => String::new() + BG_BLUE + WHITE + BOLD + " SMALL " + RESET,
             "criteria_not_met" => String::new() + BG_YELLOW + WHITE + BOLD + " SKIP " + RESET,
+            "too-large" => String::new() + BG_RED + WHITE + BOLD + " TOO LARGE " + RESET,
+            "unsupported-type" => String::new() + BG_YELLOW + WHITE + BOLD + " UNSUPPORTED " + RESET,
+            "too-small-for-format" => String::new() + BG_BLUE + WHITE + BOLD + " SMALL PNG/GIF " + RESET,
+            "already-efficient" => String::new() + BG_GREEN + WHITE + BOLD + " EFFICIENT " + RESET,
+            "unlikely-to-benefit" => String::new() + BG_GREEN + WHITE + BOLD + " NO-GAIN " + RESET,
+            "animated" => String::new() + BG_MAGENTA + WHITE + BOLD + " ANIMATED " + RESET,
+            "svg" => String::new() + BG_MAGENTA + WHITE + BOLD + " SVG " + RESET,
+            "no-transform" => String::new() + BG_YELLOW + WHITE + BOLD + " NO-TRANSFORM " + RESET,
             "non-image" => String::new() + BG_MAGENTA + WHITE + BOLD + " NON-IMG " + RESET,
             _ => String::new() + BG_BLUE + WHITE + BOLD + " " + &reason.to_uppercase() + " " + RESET,
         };
@@ -0,0 +1,105 @@
+// negative_cache.rs - Short-lived memory of failed upstream fetches
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+/// Remembers failures for a while, so an image that keeps failing isn't
+/// fetched again for every request referencing it
+#[derive(Debug)]
+pub struct NegativeCache<V> {
+    capacity: usize,
+    entries: DashMap<String, (Instant, V)>,
+}
+
+impl<V: Clone> NegativeCache<V> {
+    /// A `capacity` of 0 disables the cache
+    pub fn new(capacity: usize) -> Self {
+        NegativeCache {
+            capacity,
+            entries: DashMap::new(),
+        }
+    }
+
+    /// The failure remembered for `key`, if it hasn't expired
+    pub fn get(&self, key: &str) -> Option<V> {
+        let entry = self.entries.get(key)?;
+        let (expires, value) = &*entry;
+        if Instant::now() < *expires {
+            return Some(value.clone());
+        }
+        drop(entry);
+        self.entries.remove_if(key, |_, (expires, _)| Instant::now() >= *expires);
+        None
+    }
+
+    /// Remember a failure for `ttl`
+    pub fn insert(&self, key: &str, value: V, ttl: Duration) {
+        if self.capacity == 0 || ttl.is_zero() {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(key) {
+            self.remove_expired();
+            // Forgetting a failure only costs one more fetch
+            if self.entries.len() >= self.capacity {
+                self.entries.clear();
+            }
+        }
+        self.entries.insert(key.to_string(), (Instant::now() + ttl, value));
+    }
+
+    /// Number of failures remembered, expired ones included
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn remove_expired(&self) {
+        let now = Instant::now();
+        self.entries.retain(|_, (expires, _)| now < *expires);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_entries_expire() {
+        let cache = NegativeCache::new(8);
+        cache.insert("a", 404, Duration::from_secs(300));
+        cache.insert("b", 503, Duration::from_secs(30));
+        assert_eq!(cache.get("a"), Some(404));
+        assert_eq!(cache.get("b"), Some(503));
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(cache.get("a"), Some(404));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(270)).await;
+        cache.remove_expired();
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_capacity_is_bounded() {
+        let cache = NegativeCache::new(2);
+        cache.insert("a", 1, Duration::from_secs(10));
+        cache.insert("b", 2, Duration::from_secs(60));
+        tokio::time::advance(Duration::from_secs(10)).await;
+
+        // Expired entries make room first
+        cache.insert("c", 3, Duration::from_secs(60));
+        assert_eq!(cache.get("b"), Some(2));
+        assert_eq!(cache.get("c"), Some(3));
+
+        cache.insert("d", 4, Duration::from_secs(60));
+        assert!(cache.len() <= 2);
+        assert_eq!(cache.get("d"), Some(4));
+
+        let disabled = NegativeCache::new(0);
+        disabled.insert("a", 1, Duration::from_secs(10));
+        assert_eq!(disabled.get("a"), None);
+    }
+}
@@ -0,0 +1,115 @@
+// negotiate.rs - Output format negotiation from the client's Accept header
+
+use crate::compress::OutputFormat;
+
+/// Output formats for a request, most preferred first. An explicit
+/// `format` wins, then `jpeg=1`, then the `Accept` header. AVIF and WebP
+/// are only picked when the client names them: browsers that can't decode
+/// them still send `image/*` and `*/*`. JPEG always comes last, since every
+/// client takes it. Without an `Accept` header the proxy keeps its AVIF
+/// default
+pub fn negotiate(accept: Option<&str>, format: Option<OutputFormat>, jpeg: bool) -> Vec<OutputFormat> {
+    if let Some(format) = format {
+        return vec![format];
+    }
+    if jpeg {
+        return vec![OutputFormat::Jpeg];
+    }
+    let Some(accept) = accept.filter(|v| !v.trim().is_empty()) else {
+        return vec![OutputFormat::Avif, OutputFormat::Jpeg];
+    };
+
+    let mut accepted: Vec<(OutputFormat, f32)> = [OutputFormat::Avif, OutputFormat::WebP]
+        .into_iter()
+        .filter_map(|format| {
+            let q = quality_of(accept, &format!("image/{}", format.as_str()))?;
+            (q > 0.0).then_some((format, q))
+        })
+        .collect();
+    // Stable, so AVIF stays ahead of WebP at the same q-value
+    accepted.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    let mut formats: Vec<OutputFormat> = accepted.into_iter().map(|(format, _)| format).collect();
+    formats.push(OutputFormat::Jpeg);
+    formats
+}
+
+/// The q-value `accept` gives `media_type` by name, ignoring wildcards.
+/// Entries with an invalid q-value don't count
+fn quality_of(accept: &str, media_type: &str) -> Option<f32> {
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            if !parts.next()?.eq_ignore_ascii_case(media_type) {
+                return None;
+            }
+            let q = parts
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, value)| value.trim().parse::<f32>().ok())?;
+            (0.0..=1.0).contains(&q).then_some(q)
+        })
+        .reduce(f32::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OutputFormat::{Avif, Jpeg, Png, WebP};
+
+    #[test]
+    fn test_browser_accept_headers() {
+        let cases: &[(&str, &[OutputFormat])] = &[
+            // Chrome
+            ("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8", &[Avif, WebP, Jpeg]),
+            // Firefox
+            ("image/avif,image/webp,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5", &[Avif, WebP, Jpeg]),
+            // Safari 17
+            (
+                "image/webp,image/avif,image/jxl,image/heic,image/heic-sequence,video/*;q=0.8,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5",
+                &[Avif, WebP, Jpeg],
+            ),
+            // Safari 14, before AVIF
+            ("image/webp,image/png,image/svg+xml,image/*;q=0.8,video/*;q=0.8,*/*;q=0.5", &[WebP, Jpeg]),
+            // Old Firefox, before WebP
+            ("image/png,image/*;q=0.8,*/*;q=0.5", &[Jpeg]),
+            // curl and most HTTP libraries
+            ("*/*", &[Jpeg]),
+        ];
+        for (accept, expected) in cases {
+            assert_eq!(negotiate(Some(accept), None, false), *expected, "{}", accept);
+        }
+    }
+
+    #[test]
+    fn test_q_values() {
+        let cases: &[(&str, &[OutputFormat])] = &[
+            ("image/webp;q=0.9, image/avif;q=0.5", &[WebP, Avif, Jpeg]),
+            ("image/avif;q=0, image/webp", &[WebP, Jpeg]),
+            ("image/avif;q=0.0,image/webp;q=0", &[Jpeg]),
+            ("IMAGE/AVIF ; Q=0.4 , image/webp;q=0.4", &[Avif, WebP, Jpeg]),
+            ("image/avif;level=1", &[Avif, Jpeg]),
+            // An unusable q-value drops the entry
+            ("image/avif;q=high,image/webp;q=2", &[Jpeg]),
+            // The highest of repeated entries counts
+            ("image/avif;q=0.1,image/webp;q=0.5,image/avif", &[Avif, WebP, Jpeg]),
+        ];
+        for (accept, expected) in cases {
+            assert_eq!(negotiate(Some(accept), None, false), *expected, "{}", accept);
+        }
+    }
+
+    #[test]
+    fn test_query_overrides_accept() {
+        let chrome = Some("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8");
+        assert_eq!(negotiate(chrome, Some(Png), false), vec![Png]);
+        assert_eq!(negotiate(chrome, Some(Avif), true), vec![Avif]);
+        assert_eq!(negotiate(chrome, None, true), vec![Jpeg]);
+        assert_eq!(negotiate(Some("*/*"), Some(WebP), false), vec![WebP]);
+
+        // No Accept header keeps the AVIF default
+        assert_eq!(negotiate(None, None, false), vec![Avif, Jpeg]);
+        assert_eq!(negotiate(Some(" "), None, false), vec![Avif, Jpeg]);
+    }
+}
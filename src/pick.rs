@@ -21,6 +21,17 @@ pub fn pick(source: &HashMap<String, String>, properties: &[&str]) -> HashMap<St
     result
 }
 
+/// Copy of `source` with the values of specific properties hidden, for logging
+pub fn redacted(source: &HashMap<String, String>, properties: &[&str]) -> HashMap<String, String> {
+    source
+        .iter()
+        .map(|(k, v)| {
+            let hidden = properties.iter().any(|prop| prop.eq_ignore_ascii_case(k));
+            (k.clone(), if hidden { "[redacted]".to_string() } else { v.clone() })
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -45,4 +56,17 @@ mod tests {
         let result = pick(&source, &["user-agent", "accept"]);
         assert!(result.is_empty());
     }
+
+    #[test]
+    fn test_redacted() {
+        let mut source = HashMap::new();
+        source.insert("Authorization".to_string(), "Bearer secret".to_string());
+        source.insert("accept".to_string(), "image/webp".to_string());
+
+        let result = redacted(&source, &["authorization", "cookie"]);
+
+        assert_eq!(result.get("Authorization"), Some(&"[redacted]".to_string()));
+        assert_eq!(result.get("accept"), Some(&"image/webp".to_string()));
+        assert_eq!(result.len(), 2);
+    }
 }
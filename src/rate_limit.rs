@@ -0,0 +1,370 @@
+// rate_limit.rs - Per-host pacing and concurrency limits for upstream
+// fetches, and per-client request limits
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Host key that applies to every host without its own limit
+const ANY_HOST: &str = "*";
+
+/// Buckets kept before full (idle) ones are dropped
+const MAX_BUCKETS: usize = 1024;
+
+/// Sustained request rate and burst allowance for one host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// Parse limits in the `HOST_RATE_LIMITS` format: comma separated
+/// `<host>=<requests per second>[:<burst>]` entries, where `*` matches any
+/// host without its own entry, e.g. `img.example.com=2:5,*=20`. The burst
+/// defaults to one second's worth of requests
+pub fn parse_rate_limits(spec: &str) -> Result<HashMap<String, RateLimit>, String> {
+    let mut limits = HashMap::new();
+
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (host, limit) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("expected <host>=<rate>, got \"{}\"", entry))?;
+        let (rate, burst) = match limit.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (limit, None),
+        };
+
+        let per_second = rate
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|r| r.is_finite() && *r > 0.0)
+            .ok_or_else(|| format!("invalid rate \"{}\" for {}", rate, host))?;
+        let burst = match burst {
+            Some(burst) => burst
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|&b| b > 0)
+                .ok_or_else(|| format!("invalid burst \"{}\" for {}", burst, host))?,
+            None => (per_second.ceil() as u32).max(1),
+        };
+
+        limits.insert(host.trim().to_ascii_lowercase(), RateLimit { per_second, burst });
+    }
+
+    Ok(limits)
+}
+
+/// Token bucket state for one host
+#[derive(Debug)]
+struct Bucket {
+    /// Negative when callers have reserved tokens that haven't refilled yet
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter keyed by upstream host. Hosts without a
+/// configured limit pass straight through, unless they asked us to back off
+#[derive(Debug, Default)]
+pub struct HostRateLimiter {
+    limits: HashMap<String, RateLimit>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// When hosts that answered 429 take requests again
+    paused_until: DashMap<String, Instant>,
+}
+
+impl HostRateLimiter {
+    pub fn new(limits: HashMap<String, RateLimit>) -> Self {
+        HostRateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+            paused_until: DashMap::new(),
+        }
+    }
+
+    /// Wait until a request to `host` fits within its limit
+    pub async fn acquire(&self, host: &str) {
+        if let Some(wait) = self.paused_for(host) {
+            tokio::time::sleep(wait).await;
+        }
+        if let Some(wait) = self.reserve(host) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Hold requests to `host` for `wait`, as its Retry-After asked
+    pub fn back_off(&self, host: &str, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut paused = self.paused_until.entry(host.to_ascii_lowercase()).or_insert(until);
+        *paused = (*paused).max(until);
+    }
+
+    /// How much longer `host` asked us to wait, if at all
+    pub fn paused_for(&self, host: &str) -> Option<Duration> {
+        let host = host.to_ascii_lowercase();
+        let now = Instant::now();
+        let until = *self.paused_until.get(&host)?;
+        if until <= now {
+            self.paused_until.remove_if(&host, |_, until| *until <= now);
+            return None;
+        }
+        Some(until - now)
+    }
+
+    /// Take a token for `host`, returning how long to wait before using it
+    fn reserve(&self, host: &str) -> Option<Duration> {
+        let host = host.to_ascii_lowercase();
+        let limit = *self.limits.get(&host).or_else(|| self.limits.get(ANY_HOST))?;
+        let burst = limit.burst as f64;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&host) {
+            // A bucket that has refilled is the same as no bucket
+            buckets.retain(|key, bucket| match self.limits.get(key).or_else(|| self.limits.get(ANY_HOST)) {
+                Some(limit) => {
+                    let refilled = now.duration_since(bucket.updated).as_secs_f64() * limit.per_second;
+                    bucket.tokens + refilled < limit.burst as f64
+                }
+                None => false,
+            });
+        }
+        let bucket = buckets.entry(host).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * limit.per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(burst) - 1.0;
+        bucket.updated = now;
+
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / limit.per_second))
+    }
+}
+
+/// Token bucket rate limiter keyed by client IP. Requests over the limit
+/// are refused rather than delayed, and don't use up tokens
+#[derive(Debug, Default)]
+pub struct ClientRateLimiter {
+    limit: Option<RateLimit>,
+    buckets: DashMap<IpAddr, Bucket>,
+}
+
+impl ClientRateLimiter {
+    /// No limit lets every request through
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        ClientRateLimiter {
+            limit,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take a token for `client`, or say how long until one is available
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * limit.per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(limit.burst as f64);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.per_second));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Forget clients whose buckets have refilled, which is the same as
+    /// having no bucket
+    pub fn remove_idle(&self) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            let refilled = now.duration_since(bucket.updated).as_secs_f64() * limit.per_second;
+            bucket.tokens + refilled < limit.burst as f64
+        });
+    }
+
+    /// Number of clients currently tracked
+    pub fn client_count(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+/// Concurrent fetch limit per upstream host, so one slow host can't take
+/// every global fetch slot
+#[derive(Debug)]
+pub struct HostSemaphores {
+    per_host: usize,
+    semaphores: DashMap<String, Arc<Semaphore>>,
+}
+
+impl HostSemaphores {
+    pub fn new(per_host: usize) -> Self {
+        HostSemaphores {
+            per_host: per_host.max(1),
+            semaphores: DashMap::new(),
+        }
+    }
+
+    /// Wait for a fetch slot for `host`
+    pub async fn acquire(&self, host: &str) -> OwnedSemaphorePermit {
+        let semaphore = self
+            .semaphores
+            .entry(host.to_ascii_lowercase())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+            .clone();
+        semaphore.acquire_owned().await.expect("host semaphores are never closed")
+    }
+
+    /// Forget hosts with no fetch running or waiting
+    pub fn remove_idle(&self) {
+        self.semaphores
+            .retain(|_, semaphore| Arc::strong_count(semaphore) > 1 || semaphore.available_permits() < self.per_host);
+    }
+
+    /// Number of hosts currently tracked
+    pub fn host_count(&self) -> usize {
+        self.semaphores.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limits() {
+        let limits = parse_rate_limits("img.example.com=2:5, *=0.5 ,CDN.example.com=10").unwrap();
+        assert_eq!(limits.len(), 3);
+        assert_eq!(limits["img.example.com"], RateLimit { per_second: 2.0, burst: 5 });
+        assert_eq!(limits["*"], RateLimit { per_second: 0.5, burst: 1 });
+        assert_eq!(limits["cdn.example.com"], RateLimit { per_second: 10.0, burst: 10 });
+        assert!(parse_rate_limits("").unwrap().is_empty());
+
+        assert!(parse_rate_limits("img.example.com").is_err());
+        assert!(parse_rate_limits("img.example.com=fast").is_err());
+        assert!(parse_rate_limits("img.example.com=0").is_err());
+        assert!(parse_rate_limits("img.example.com=-1").is_err());
+        assert!(parse_rate_limits("img.example.com=2:0").is_err());
+        assert!(parse_rate_limits("img.example.com=2:x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_hosts_are_not_delayed() {
+        let limiter = HostRateLimiter::default();
+        let started = Instant::now();
+        for _ in 0..100 {
+            limiter.acquire("example.com").await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(20));
+
+        let limiter = HostRateLimiter::new(parse_rate_limits("slow.example.com=1").unwrap());
+        assert_eq!(limiter.reserve("other.example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn test_limited_host_is_paced() {
+        let limiter = HostRateLimiter::new(parse_rate_limits("img.example.com=20:2").unwrap());
+
+        // The burst goes through at once, then one request every 50 ms
+        assert_eq!(limiter.reserve("img.example.com"), None);
+        assert_eq!(limiter.reserve("IMG.example.com"), None);
+        let started = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire("img.example.com").await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(400), "took {:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_back_off_pauses_the_host() {
+        let limiter = HostRateLimiter::default();
+        limiter.back_off("IMG.example.com", Duration::from_secs(5));
+        limiter.back_off("img.example.com", Duration::from_secs(2));
+        assert_eq!(limiter.paused_for("img.example.com"), Some(Duration::from_secs(5)));
+        assert_eq!(limiter.paused_for("other.example.com"), None);
+
+        let started = Instant::now();
+        limiter.acquire("img.example.com").await;
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+        assert_eq!(limiter.paused_for("img.example.com"), None);
+    }
+
+    #[test]
+    fn test_wildcard_limits_each_host_separately() {
+        let limiter = HostRateLimiter::new(parse_rate_limits("*=1:1").unwrap());
+        assert_eq!(limiter.reserve("a.example.com"), None);
+        assert_eq!(limiter.reserve("b.example.com"), None);
+        assert!(limiter.reserve("a.example.com").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_limit_refuses_past_the_burst() {
+        let limiter = ClientRateLimiter::new(Some(RateLimit { per_second: 0.5, burst: 2 }));
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap());
+        assert_eq!(limiter.check(a), Ok(()));
+        assert_eq!(limiter.check(a), Ok(()));
+        assert_eq!(limiter.check(a), Err(Duration::from_secs(2)));
+        // Refused requests don't push the next token further out
+        assert_eq!(limiter.check(a), Err(Duration::from_secs(2)));
+        assert_eq!(limiter.check(b), Ok(()));
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(limiter.check(a), Ok(()));
+        assert!(limiter.check(a).is_err());
+
+        // Only full buckets are forgotten
+        tokio::time::advance(Duration::from_secs(3)).await;
+        limiter.remove_idle();
+        assert_eq!(limiter.client_count(), 1);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        limiter.remove_idle();
+        assert_eq!(limiter.client_count(), 0);
+
+        let unlimited = ClientRateLimiter::default();
+        assert!((0..100).all(|_| unlimited.check(a).is_ok()));
+        assert_eq!(unlimited.client_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_saturated_host_does_not_block_others() {
+        let semaphores = HostSemaphores::new(2);
+        let _a1 = semaphores.acquire("a.example.com").await;
+        let _a2 = semaphores.acquire("a.example.com").await;
+
+        let timeout = Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, semaphores.acquire("A.example.com")).await.is_err());
+        assert!(tokio::time::timeout(timeout, semaphores.acquire("b.example.com")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_idle_hosts_are_removed() {
+        let semaphores = HostSemaphores::new(2);
+        let busy = semaphores.acquire("busy.example.com").await;
+        drop(semaphores.acquire("idle.example.com").await);
+        assert_eq!(semaphores.host_count(), 2);
+
+        semaphores.remove_idle();
+        assert_eq!(semaphores.host_count(), 1);
+
+        drop(busy);
+        semaphores.remove_idle();
+        assert_eq!(semaphores.host_count(), 0);
+    }
+}
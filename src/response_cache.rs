@@ -0,0 +1,244 @@
+// response_cache.rs - In-memory LRU cache of finished responses
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::etag::Validators;
+
+/// Least recently used entries go first once either the entry count or the
+/// total size is over its limit
+#[derive(Debug)]
+pub struct ResponseCache<V> {
+    max_entries: usize,
+    max_bytes: usize,
+    /// Age at which entries go stale, unset when they never do
+    ttl: Option<Duration>,
+    state: Mutex<LruState<V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct LruState<V> {
+    entries: HashMap<String, Entry<V>>,
+    /// Keys by last use, oldest first
+    order: BTreeMap<u64, String>,
+    next_use: u64,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    size: usize,
+    last_use: u64,
+    /// Upstream validators the value was made from
+    validators: Validators,
+    /// When the upstream last vouched for the value
+    validated_at: SystemTime,
+}
+
+/// A cached value, with what it takes to revalidate it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cached<V> {
+    pub value: V,
+    pub validators: Validators,
+    pub validated_at: SystemTime,
+    /// Older than the TTL, so the upstream should confirm it before it is
+    /// served again
+    pub stale: bool,
+}
+
+/// Whether a value the upstream vouched for at `validated_at` has outlived
+/// `ttl`
+pub fn is_stale(validated_at: SystemTime, ttl: Option<Duration>) -> bool {
+    ttl.is_some_and(|ttl| validated_at.elapsed().is_ok_and(|age| age >= ttl))
+}
+
+impl<V: Clone> ResponseCache<V> {
+    /// A limit of 0 disables the cache
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        ResponseCache {
+            max_entries,
+            max_bytes,
+            ttl: None,
+            state: Mutex::new(LruState {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                next_use: 0,
+                bytes: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Entries go stale `ttl` after they were last validated. A TTL of 0
+    /// keeps them fresh until evicted
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = (!ttl.is_zero()).then_some(ttl);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_entries > 0 && self.max_bytes > 0
+    }
+
+    /// The value cached for `key`, which becomes the most recently used
+    pub fn get(&self, key: &str) -> Option<Cached<V>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let use_id = state.next_use;
+        let Some(entry) = state.entries.get_mut(key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        state.order.remove(&entry.last_use);
+        state.order.insert(use_id, key.to_string());
+        entry.last_use = use_id;
+        state.next_use += 1;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(Cached {
+            value: entry.value.clone(),
+            validators: entry.validators.clone(),
+            validated_at: entry.validated_at,
+            stale: is_stale(entry.validated_at, self.ttl),
+        })
+    }
+
+    /// Cache `value`, taking `size` bytes of the budget, along with the
+    /// upstream validators it was made from and when they were last checked.
+    /// Values larger than the whole budget aren't cached
+    pub fn insert(&self, key: &str, value: V, size: usize, validators: Validators, validated_at: SystemTime) {
+        if !self.is_enabled() || size > self.max_bytes {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.entries.remove(key) {
+            state.order.remove(&old.last_use);
+            state.bytes -= old.size;
+        }
+
+        let last_use = state.next_use;
+        state.next_use += 1;
+        state.order.insert(last_use, key.to_string());
+        let entry = Entry {
+            value,
+            size,
+            last_use,
+            validators,
+            validated_at,
+        };
+        state.entries.insert(key.to_string(), entry);
+        state.bytes += size;
+
+        while state.entries.len() > self.max_entries || state.bytes > self.max_bytes {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = state.entries.remove(&oldest) {
+                state.bytes -= evicted.size;
+            }
+        }
+    }
+
+    /// Start the TTL of `key` over, once the upstream confirmed it unchanged
+    pub fn refresh(&self, key: &str) {
+        if let Some(entry) = self.state.lock().unwrap().entries.get_mut(key) {
+            entry.validated_at = SystemTime::now();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// Bytes of the budget in use
+    pub fn bytes(&self) -> usize {
+        self.state.lock().unwrap().bytes
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(cache: &ResponseCache<&'static str>, key: &str) -> Option<&'static str> {
+        cache.get(key).map(|cached| cached.value)
+    }
+
+    #[test]
+    fn test_byte_budget_evicts_least_recently_used() {
+        let cache = ResponseCache::new(10, 100);
+        cache.insert("a", "a", 40, Validators::default(), SystemTime::now());
+        cache.insert("b", "b", 40, Validators::default(), SystemTime::now());
+        assert_eq!(value(&cache, "a"), Some("a"));
+
+        // "b" is the least recently used, and 30 more bytes don't fit with it
+        cache.insert("c", "c", 30, Validators::default(), SystemTime::now());
+        assert_eq!(value(&cache, "b"), None);
+        assert_eq!(value(&cache, "a"), Some("a"));
+        assert_eq!(value(&cache, "c"), Some("c"));
+        assert_eq!(cache.bytes(), 70);
+
+        // Replacing an entry frees its old size
+        cache.insert("a", "A", 10, Validators::default(), SystemTime::now());
+        assert_eq!(cache.bytes(), 40);
+        assert_eq!(value(&cache, "a"), Some("A"));
+
+        // Too large for the whole budget
+        cache.insert("d", "d", 101, Validators::default(), SystemTime::now());
+        assert_eq!(value(&cache, "d"), None);
+        assert_eq!(cache.len(), 2);
+        assert_eq!((cache.hits(), cache.misses()), (4, 2));
+    }
+
+    #[test]
+    fn test_entry_limit() {
+        let cache = ResponseCache::new(2, 100);
+        for key in ["a", "b", "c"] {
+            cache.insert(key, key, 1, Validators::default(), SystemTime::now());
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(value(&cache, "a"), None);
+
+        let disabled = ResponseCache::new(0, 100);
+        disabled.insert("a", "a", 1, Validators::default(), SystemTime::now());
+        assert!(!disabled.is_enabled());
+        assert_eq!(disabled.len(), 0);
+    }
+
+    #[test]
+    fn test_entries_go_stale_until_refreshed() {
+        let cache = ResponseCache::new(10, 100).with_ttl(Duration::from_secs(60));
+        let validators = Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        };
+        cache.insert("fresh", "a", 1, validators.clone(), SystemTime::now());
+        let cached = cache.get("fresh").unwrap();
+        assert!(!cached.stale);
+        assert_eq!(cached.validators, validators);
+
+        let long_ago = SystemTime::now() - Duration::from_secs(61);
+        cache.insert("old", "b", 1, validators, long_ago);
+        assert!(cache.get("old").unwrap().stale);
+        cache.refresh("old");
+        assert!(!cache.get("old").unwrap().stale);
+
+        // Without a TTL nothing goes stale
+        let cache = ResponseCache::new(10, 100).with_ttl(Duration::ZERO);
+        cache.insert("old", "b", 1, Validators::default(), long_ago);
+        assert!(!cache.get("old").unwrap().stale);
+    }
+}
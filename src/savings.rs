@@ -0,0 +1,151 @@
+// savings.rs - Lifetime totals of the bandwidth the proxy saved
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::http::{HeaderMap, StatusCode};
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::time::Instant;
+
+/// Counters for every compression request answered since startup
+#[derive(Debug)]
+pub struct SavingsStats {
+    started: Instant,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    bypasses: AtomicU64,
+    compressions: AtomicU64,
+    original_bytes: AtomicU64,
+    served_bytes: AtomicU64,
+    bytes_saved: AtomicU64,
+    /// Compressed responses by output format
+    formats: DashMap<String, AtomicU64>,
+}
+
+impl Default for SavingsStats {
+    fn default() -> Self {
+        SavingsStats {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            bypasses: AtomicU64::new(0),
+            compressions: AtomicU64::new(0),
+            original_bytes: AtomicU64::new(0),
+            served_bytes: AtomicU64::new(0),
+            bytes_saved: AtomicU64::new(0),
+            formats: DashMap::new(),
+        }
+    }
+}
+
+/// Snapshot of the counters, as `/stats` reports them
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Savings {
+    pub requests: u64,
+    pub errors: u64,
+    pub bypasses: u64,
+    pub compressions: u64,
+    pub original_bytes: u64,
+    pub served_bytes: u64,
+    pub bytes_saved: u64,
+    pub formats: BTreeMap<String, u64>,
+}
+
+/// Bytes compression saved on a response, from its `x-bytes-saved`
+pub fn bytes_saved(headers: &HeaderMap) -> u64 {
+    headers
+        .get("x-bytes-saved")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+        .map_or(0, |saved| saved.max(0) as u64)
+}
+
+impl SavingsStats {
+    /// Count a response served to a client, with a body of `len` bytes
+    pub fn record(&self, status: StatusCode, headers: &HeaderMap, len: usize) {
+        let len = len as u64;
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if status.is_client_error() || status.is_server_error() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if headers.contains_key("x-bypass-reason") {
+            self.bypasses.fetch_add(1, Ordering::Relaxed);
+            self.original_bytes.fetch_add(len, Ordering::Relaxed);
+            self.served_bytes.fetch_add(len, Ordering::Relaxed);
+        } else if headers.contains_key("x-compressed-by") {
+            let saved = bytes_saved(headers);
+            self.compressions.fetch_add(1, Ordering::Relaxed);
+            self.original_bytes.fetch_add(len + saved, Ordering::Relaxed);
+            self.served_bytes.fetch_add(len, Ordering::Relaxed);
+            self.bytes_saved.fetch_add(saved, Ordering::Relaxed);
+
+            let format = headers
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(';').next())
+                .map(|v| v.trim().trim_start_matches("image/").to_ascii_lowercase())
+                .unwrap_or_default();
+            self.formats
+                .entry(format)
+                .or_insert_with(|| AtomicU64::new(0))
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn snapshot(&self) -> Savings {
+        Savings {
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            bypasses: self.bypasses.load(Ordering::Relaxed),
+            compressions: self.compressions.load(Ordering::Relaxed),
+            original_bytes: self.original_bytes.load(Ordering::Relaxed),
+            served_bytes: self.served_bytes.load(Ordering::Relaxed),
+            bytes_saved: self.bytes_saved.load(Ordering::Relaxed),
+            formats: self
+                .formats
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_record() {
+        let stats = SavingsStats::default();
+        let mut compressed = HeaderMap::new();
+        compressed.insert("x-compressed-by", HeaderValue::from_static("bandwidth-hero"));
+        compressed.insert("x-bytes-saved", HeaderValue::from_static("900"));
+        compressed.insert("content-type", HeaderValue::from_static("image/avif"));
+        let mut bypassed = HeaderMap::new();
+        bypassed.insert("x-bypass-reason", HeaderValue::from_static("already_small"));
+
+        stats.record(StatusCode::OK, &compressed, 100);
+        stats.record(StatusCode::OK, &compressed, 100);
+        stats.record(StatusCode::OK, &bypassed, 500);
+        stats.record(StatusCode::BAD_GATEWAY, &HeaderMap::new(), 80);
+        stats.record(StatusCode::NOT_MODIFIED, &HeaderMap::new(), 0);
+
+        let savings = stats.snapshot();
+        assert_eq!(savings.requests, 5);
+        assert_eq!(savings.errors, 1);
+        assert_eq!(savings.compressions, 2);
+        assert_eq!(savings.bypasses, 1);
+        assert_eq!(savings.original_bytes, 2500);
+        assert_eq!(savings.served_bytes, 700);
+        assert_eq!(savings.bytes_saved, 1800);
+        assert_eq!(savings.formats, BTreeMap::from([("avif".to_string(), 2)]));
+    }
+}
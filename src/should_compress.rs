@@ -1,54 +1,434 @@
 // should_compress.rs - Determines if an image should be compressed
 
-/// Configuration constants for compression decisions
+use std::collections::HashMap;
+
+use crate::compress::OutputFormat;
+
+/// Image types that can carry their own thresholds
+const THRESHOLD_TYPES: &[&str] = &[
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "image/bmp",
+    "image/tiff",
+    "image/heic",
+    "image/heif",
+    "image/svg+xml",
+];
+
+/// Default for the opaque PNG/GIF minimum size
+pub const DEFAULT_MIN_TRANSPARENT_COMPRESS_LENGTH: u64 = 102400;
+
+/// Size thresholds for one source type; unset values use the global ones
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TypeThresholds {
+    /// Smallest source worth compressing
+    pub min_size: Option<u64>,
+    /// Smallest opaque source worth compressing; opacity usually means a
+    /// palette image that is already compact
+    pub min_transparent_size: Option<u64>,
+    /// Largest source worth decoding
+    pub max_size: Option<u64>,
+}
+
+/// Default per-type thresholds: opaque PNGs and GIFs only pay off when large
+pub fn default_type_thresholds(min_transparent_compress_length: u64) -> HashMap<&'static str, TypeThresholds> {
+    let opaque = TypeThresholds {
+        min_transparent_size: Some(min_transparent_compress_length),
+        ..TypeThresholds::default()
+    };
+    HashMap::from([("image/png", opaque), ("image/gif", opaque)])
+}
+
+/// Parse per-type overrides in the `TYPE_THRESHOLDS` format: comma
+/// separated `<type>:<min>[:<min opaque>[:<max>]]` entries, where sizes
+/// take an optional `k` or `m` suffix and empty fields keep the default,
+/// e.g. `image/png:100k,image/jpeg:2k,image/gif::50k:1m`
+pub fn parse_type_thresholds(spec: &str) -> Result<HashMap<&'static str, TypeThresholds>, String> {
+    let mut thresholds = HashMap::new();
+
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut fields = entry.split(':').map(str::trim);
+        let image_type = fields.next().unwrap_or_default();
+        let image_type = THRESHOLD_TYPES
+            .iter()
+            .copied()
+            .find(|t| t.eq_ignore_ascii_case(image_type))
+            .ok_or_else(|| format!("unknown image type \"{}\"", image_type))?;
+
+        let sizes = fields.map(parse_size).collect::<Result<Vec<_>, _>>()?;
+        if sizes.is_empty() || sizes.len() > 3 {
+            return Err(format!("expected 1 to 3 sizes for {}, got \"{}\"", image_type, entry));
+        }
+
+        thresholds.insert(
+            image_type,
+            TypeThresholds {
+                min_size: sizes[0],
+                min_transparent_size: sizes.get(1).copied().flatten(),
+                max_size: sizes.get(2).copied().flatten(),
+            },
+        );
+    }
+
+    Ok(thresholds)
+}
+
+/// Parse a byte size like `2048`, `100k` or `5m`; empty means unset
+pub fn parse_size(value: &str) -> Result<Option<u64>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    let (digits, multiplier) = match value.as_bytes()[value.len() - 1].to_ascii_lowercase() {
+        b'k' => (&value[..value.len() - 1], 1024),
+        b'm' => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Some)
+        .ok_or_else(|| format!("invalid size \"{}\"", value))
+}
+
+/// Size thresholds for compression decisions
+#[derive(Clone, Debug)]
 pub struct Config {
+    /// Smallest source worth compressing to JPEG, WebP or PNG
     pub min_compress_length: u64,
-    pub min_transparent_compress_length: u64,
+    /// Smallest source worth compressing to AVIF or JPEG XL
+    pub min_compress_length_avif: u64,
     pub max_original_size: u64,
+    /// Overrides for specific source types, keyed by lowercase MIME type
+    pub type_thresholds: HashMap<&'static str, TypeThresholds>,
+    /// WebP sources below this size are already efficient enough
+    pub min_webp_compress_length: u64,
+    /// AVIF sources below this size are already efficient enough
+    pub min_avif_compress_length: u64,
+    /// Sources smaller than this multiple of their estimated output size
+    /// are unlikely to shrink enough to be worth encoding
+    pub benefit_margin: f64,
+    /// Estimated JPEG output bytes per pixel at quality 40
+    pub jpeg_bytes_per_pixel: f64,
+    /// Estimated AVIF output bytes per pixel at quality 40
+    pub avif_bytes_per_pixel: f64,
 }
 
+/// Sources above this size are always worth an encode attempt
+pub const MAX_BENEFIT_CHECK_SIZE: u64 = 200 * 1024;
+
 impl Default for Config {
     fn default() -> Self {
         Config {
-            min_compress_length: 2048,
-            min_transparent_compress_length: 102400,
+            min_compress_length: 10240,
+            min_compress_length_avif: 4096,
             max_original_size: 5 * 1024 * 1024,
+            type_thresholds: default_type_thresholds(DEFAULT_MIN_TRANSPARENT_COMPRESS_LENGTH),
+            min_webp_compress_length: 64 * 1024,
+            min_avif_compress_length: 32 * 1024,
+            benefit_margin: 1.3,
+            jpeg_bytes_per_pixel: 0.1,
+            avif_bytes_per_pixel: 0.05,
         }
     }
 }
 
-/// Determines if an image should be compressed based on type, size, and transparency
+impl Config {
+    /// Reject thresholds that contradict each other
+    pub fn validate(&self) -> Result<(), String> {
+        let mins = [
+            ("min_compress_length", self.min_compress_length),
+            ("min_compress_length_avif", self.min_compress_length_avif),
+            ("min_webp_compress_length", self.min_webp_compress_length),
+            ("min_avif_compress_length", self.min_avif_compress_length),
+        ];
+        for (name, min) in mins {
+            if min > self.max_original_size {
+                return Err(format!(
+                    "{} ({}) exceeds max_original_size ({})",
+                    name, min, self.max_original_size
+                ));
+            }
+        }
+
+        for (image_type, thresholds) in &self.type_thresholds {
+            let max = thresholds.max_size.unwrap_or(self.max_original_size);
+            for min in [thresholds.min_size, thresholds.min_transparent_size].into_iter().flatten() {
+                if min > max {
+                    return Err(format!("{} minimum size ({}) exceeds its maximum ({})", image_type, min, max));
+                }
+            }
+        }
+
+        if self.benefit_margin < 0.0 {
+            return Err(format!("benefit_margin ({}) must not be negative", self.benefit_margin));
+        }
+        if self.jpeg_bytes_per_pixel < 0.0 || self.avif_bytes_per_pixel < 0.0 {
+            return Err("bytes per pixel estimates must not be negative".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Estimated size of a lossy `output` encode of `pixels` pixels at
+    /// `quality`; `None` for lossless formats
+    pub fn estimated_output_size(&self, pixels: u64, output: OutputFormat, quality: u8) -> Option<f64> {
+        let bytes_per_pixel = match output {
+            OutputFormat::Jpeg => self.jpeg_bytes_per_pixel,
+            OutputFormat::Avif => self.avif_bytes_per_pixel,
+            #[cfg(feature = "jxl")]
+            OutputFormat::Jxl => self.avif_bytes_per_pixel,
+            OutputFormat::WebP | OutputFormat::Png => return None,
+        };
+        Some(pixels as f64 * bytes_per_pixel * quality as f64 / 40.0)
+    }
+
+    /// Thresholds for `image_type`, ignoring case and parameters; unknown
+    /// types have no overrides
+    pub fn thresholds_for(&self, image_type: &str) -> TypeThresholds {
+        let essence = image_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.type_thresholds.get(essence.as_str()).copied().unwrap_or_default()
+    }
+
+    /// Smallest source worth compressing to `output`; modern codecs pay off sooner
+    pub fn min_compress_length_for(&self, output: OutputFormat) -> u64 {
+        match output {
+            OutputFormat::Avif => self.min_compress_length_avif,
+            #[cfg(feature = "jxl")]
+            OutputFormat::Jxl => self.min_compress_length_avif,
+            _ => self.min_compress_length,
+        }
+    }
+}
+
+/// Outcome of a compression decision, with the reason when skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressDecision {
+    Compress,
+    /// Below the minimum size for the source type or output format
+    TooSmall,
+    /// Above the maximum size for the source type
+    TooLarge,
+    /// Missing or undecodable content type
+    UnsupportedType,
+    /// Opaque source below its type's `min_transparent_size`
+    TransparentTooSmall,
+    /// WebP/AVIF below its per-format threshold
+    AlreadyEfficient,
+}
+
+impl CompressDecision {
+    /// `x-bypass-reason` value for a skipped image, `None` for `Compress`
+    pub fn bypass_reason(&self) -> Option<&'static str> {
+        match self {
+            CompressDecision::Compress => None,
+            CompressDecision::TooSmall => Some("already_small"),
+            CompressDecision::TooLarge => Some("too-large"),
+            CompressDecision::UnsupportedType => Some("unsupported-type"),
+            CompressDecision::TransparentTooSmall => Some("too-small-for-format"),
+            CompressDecision::AlreadyEfficient => Some("already-efficient"),
+        }
+    }
+}
+
+/// Determines if an image should be compressed to `output` based on type, size, and transparency
 pub fn should_compress(
     image_type: &str,
     size: u64,
     is_transparent: bool,
+    output: OutputFormat,
     config: &Config,
-) -> bool {
+) -> CompressDecision {
     if image_type.is_empty() {
-        return false;
+        return CompressDecision::UnsupportedType;
     }
 
+    let thresholds = config.thresholds_for(image_type);
+
     // Check size constraints
-    if size > config.max_original_size || size < config.min_compress_length {
-        return false;
+    if size > thresholds.max_size.unwrap_or(config.max_original_size) {
+        return CompressDecision::TooLarge;
+    }
+    if size < thresholds.min_size.unwrap_or_else(|| config.min_compress_length_for(output)) {
+        return CompressDecision::TooSmall;
+    }
+
+    // Modern formats only shrink meaningfully when they are large
+    if is_already_efficient(image_type, size, config) {
+        return CompressDecision::AlreadyEfficient;
     }
 
     // Check if it's a supported image type
     if !is_supported_image_type(image_type) {
+        return CompressDecision::UnsupportedType;
+    }
+
+    // Opaque palette formats are already compact unless large
+    if !is_transparent && size < thresholds.min_transparent_size.unwrap_or(0) {
+        return CompressDecision::TransparentTooSmall;
+    }
+
+    CompressDecision::Compress
+}
+
+/// Check if a source is already close to the size an encode of
+/// `output_pixels` pixels would produce, so compressing it mostly burns CPU
+pub fn is_unlikely_to_benefit(
+    size: u64,
+    output_pixels: u64,
+    output: OutputFormat,
+    quality: u8,
+    config: &Config,
+) -> bool {
+    if size > MAX_BENEFIT_CHECK_SIZE {
         return false;
     }
 
-    // Handle transparent images
-    if is_transparent {
-        return size >= config.min_compress_length;
+    match config.estimated_output_size(output_pixels, output, quality) {
+        Some(estimate) => (size as f64) < estimate * config.benefit_margin,
+        None => false,
+    }
+}
+
+/// Check if a WebP or AVIF source is too small to be worth re-encoding
+fn is_already_efficient(image_type: &str, size: u64, config: &Config) -> bool {
+    if image_type.eq_ignore_ascii_case("image/webp") {
+        return size < config.min_webp_compress_length;
+    }
+    if image_type.eq_ignore_ascii_case("image/avif") {
+        return size < config.min_avif_compress_length;
+    }
+    false
+}
+
+/// Identify an image format from its magic bytes, for upstreams that send
+/// a missing or wrong content type
+pub fn sniff_image_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some("image/jpeg");
+    }
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return Some("image/gif");
+    }
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    if data.starts_with(b"BM") && data.len() >= 14 {
+        return Some("image/bmp");
+    }
+    if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        return Some("image/tiff");
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" && matches!(&data[8..12], b"avif" | b"avis") {
+        return Some("image/avif");
+    }
+    None
+}
+
+/// Cheaply sniff whether an image may have transparent pixels, from its
+/// headers only: PNG alpha color types or a tRNS chunk, and GIF graphic
+/// control extensions with the transparent color flag set
+pub fn has_transparency(data: &[u8]) -> bool {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return png_has_transparency(data);
     }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return gif_has_transparency(data);
+    }
+    false
+}
 
-    // For non-transparent PNG/GIF, ensure they're large enough
-    if image_type.ends_with("png") || image_type.ends_with("gif") {
-        return size >= config.min_transparent_compress_length;
+/// Check the IHDR color type, then look for a tRNS chunk ahead of the pixel data
+fn png_has_transparency(data: &[u8]) -> bool {
+    // Grayscale + alpha (4) or RGBA (6)
+    if matches!(data.get(25), Some(4 | 6)) {
+        return true;
     }
 
-    true
+    let mut offset = 8;
+    while let Some(header) = data.get(offset..offset + 8) {
+        match &header[4..] {
+            b"tRNS" => return true,
+            b"IDAT" | b"IEND" => return false,
+            _ => {}
+        }
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        // Chunk length, type, data and CRC
+        offset = offset.saturating_add(12 + length);
+    }
+    false
+}
+
+/// Check graphic control extensions for a transparent color flag
+fn gif_has_transparency(data: &[u8]) -> bool {
+    visit_gif_control_blocks(data, |flags| flags & 0x01 != 0)
+}
+
+/// Check if a GIF has more than one frame, counting graphic control
+/// extensions without decoding any pixel data
+pub fn is_animated_gif(data: &[u8]) -> bool {
+    if !data.starts_with(b"GIF87a") && !data.starts_with(b"GIF89a") {
+        return false;
+    }
+
+    let mut control_blocks = 0;
+    visit_gif_control_blocks(data, |_| {
+        control_blocks += 1;
+        control_blocks > 1
+    })
+}
+
+/// Walk the GIF block structure, calling `visit` with the packed flags of
+/// each graphic control extension until it returns true. Returns false at
+/// the trailer or at the first truncated or malformed block.
+fn visit_gif_control_blocks(data: &[u8], mut visit: impl FnMut(u8) -> bool) -> bool {
+    let color_table_size = |flags: u8| if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 };
+    let skip_sub_blocks = |mut offset: usize| loop {
+        let length = *data.get(offset)? as usize;
+        offset += 1 + length;
+        if length == 0 {
+            return Some(offset);
+        }
+    };
+
+    let Some(&screen_flags) = data.get(10) else {
+        return false;
+    };
+    let mut offset = 13 + color_table_size(screen_flags);
+
+    loop {
+        let next = match data.get(offset) {
+            // Extension; graphic control extensions have a 4 byte body
+            Some(0x21) => {
+                if data.get(offset + 1) == Some(&0xF9) && data.get(offset + 2) == Some(&4) {
+                    match data.get(offset + 3) {
+                        Some(&flags) if visit(flags) => return true,
+                        Some(_) => {}
+                        None => return false,
+                    }
+                }
+                skip_sub_blocks(offset + 2)
+            }
+            // Image descriptor, optional local color table, LZW code size, then data
+            Some(0x2C) => data
+                .get(offset + 9)
+                .and_then(|&flags| skip_sub_blocks(offset + 11 + color_table_size(flags))),
+            // Trailer or garbage
+            _ => None,
+        };
+
+        match next {
+            Some(next) => offset = next,
+            None => return false,
+        }
+    }
 }
 
 /// Check if the MIME type is a supported image format
@@ -61,7 +441,32 @@ fn is_supported_image_type(image_type: &str) -> bool {
         "image/bmp",
         "image/tiff",
     ];
-    supported.iter().any(|&t| image_type.eq_ignore_ascii_case(t))
+    if supported.iter().any(|&t| image_type.eq_ignore_ascii_case(t)) {
+        return true;
+    }
+
+    // AVIF needs dav1d to decode
+    if cfg!(feature = "avif-decode") && image_type.eq_ignore_ascii_case("image/avif") {
+        return true;
+    }
+
+    // HEIC/HEIF needs libheif to decode
+    if cfg!(feature = "heif")
+        && (image_type.eq_ignore_ascii_case("image/heic") || image_type.eq_ignore_ascii_case("image/heif"))
+    {
+        return true;
+    }
+
+    // SVGs are rasterized before encoding
+    cfg!(feature = "svg") && is_svg_type(image_type)
+}
+
+/// Check if the MIME type is SVG, ignoring parameters such as charset
+pub fn is_svg_type(image_type: &str) -> bool {
+    image_type
+        .split(';')
+        .next()
+        .is_some_and(|t| t.trim().eq_ignore_ascii_case("image/svg+xml"))
 }
 
 #[cfg(test)]
@@ -71,32 +476,246 @@ mod tests {
     #[test]
     fn test_should_compress_valid_image() {
         let config = Config::default();
-        assert!(should_compress("image/jpeg", 5000, false, &config));
-        assert!(should_compress("image/png", 150000, false, &config));
+        assert_eq!(should_compress("image/jpeg", 20000, false, OutputFormat::Jpeg, &config), CompressDecision::Compress);
+        assert_eq!(should_compress("image/png", 150000, false, OutputFormat::Jpeg, &config), CompressDecision::Compress);
     }
 
     #[test]
     fn test_should_compress_too_small() {
         let config = Config::default();
-        assert!(!should_compress("image/jpeg", 1000, false, &config));
+        assert_eq!(should_compress("image/jpeg", 1000, false, OutputFormat::Jpeg, &config), CompressDecision::TooSmall);
     }
 
     #[test]
     fn test_should_compress_too_large() {
         let config = Config::default();
-        assert!(!should_compress("image/jpeg", 6 * 1024 * 1024, false, &config));
+        assert_eq!(should_compress("image/jpeg", 6 * 1024 * 1024, false, OutputFormat::Jpeg, &config), CompressDecision::TooLarge);
     }
 
     #[test]
     fn test_should_compress_unsupported_type() {
         let config = Config::default();
-        assert!(!should_compress("image/svg+xml", 5000, false, &config));
+        assert_eq!(should_compress("image/x-icon", 20000, false, OutputFormat::Jpeg, &config), CompressDecision::UnsupportedType);
+        assert_eq!(should_compress("", 20000, false, OutputFormat::Jpeg, &config), CompressDecision::UnsupportedType);
+        #[cfg(not(feature = "svg"))]
+        assert_eq!(should_compress("image/svg+xml", 20000, false, OutputFormat::Jpeg, &config), CompressDecision::UnsupportedType);
+    }
+
+    #[test]
+    fn test_should_compress_heif() {
+        let config = Config::default();
+        let expected = if cfg!(feature = "heif") { CompressDecision::Compress } else { CompressDecision::UnsupportedType };
+        assert_eq!(should_compress("image/heic", 20000, false, OutputFormat::Jpeg, &config), expected);
+        assert_eq!(should_compress("image/heif", 20000, false, OutputFormat::Jpeg, &config), expected);
+    }
+
+    #[test]
+    fn test_should_compress_modern_formats_by_size() {
+        let config = Config::default();
+
+        assert_eq!(should_compress("image/webp", 20 * 1024, false, OutputFormat::Jpeg, &config), CompressDecision::AlreadyEfficient);
+        assert_eq!(should_compress("image/webp", 500 * 1024, false, OutputFormat::Jpeg, &config), CompressDecision::Compress);
+        assert_eq!(should_compress("image/avif", 20 * 1024, false, OutputFormat::Jpeg, &config), CompressDecision::AlreadyEfficient);
+        let expected = if cfg!(feature = "avif-decode") { CompressDecision::Compress } else { CompressDecision::UnsupportedType };
+        assert_eq!(should_compress("image/avif", 500 * 1024, false, OutputFormat::Jpeg, &config), expected);
+
+        assert!(is_already_efficient("image/webp", 20 * 1024, &config));
+        assert!(is_already_efficient("image/avif", 20 * 1024, &config));
+        assert!(!is_already_efficient("image/avif", 40 * 1024, &config));
+        assert!(!is_already_efficient("image/jpeg", 20 * 1024, &config));
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn test_should_compress_svg() {
+        let config = Config::default();
+        assert_eq!(should_compress("image/svg+xml", 20000, false, OutputFormat::Jpeg, &config), CompressDecision::Compress);
+        assert_eq!(should_compress("image/svg+xml; charset=utf-8", 20000, false, OutputFormat::Jpeg, &config), CompressDecision::Compress);
+    }
+
+    fn encode_png(img: image::DynamicImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut data), image::ImageFormat::Png).unwrap();
+        data
+    }
+
+    fn encode_gif(alpha: u8) -> Vec<u8> {
+        let img = image::RgbaImage::from_fn(8, 8, |x, _| image::Rgba([x as u8 * 30, 0, 0, if x == 0 { alpha } else { 255 }]));
+        let mut data = Vec::new();
+        image::codecs::gif::GifEncoder::new(&mut data)
+            .encode_frame(image::Frame::new(img))
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_sniff_image_type() {
+        assert_eq!(sniff_image_type(b"\xff\xd8\xff\xe0\x00\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(sniff_image_type(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR"), Some("image/png"));
+        assert_eq!(sniff_image_type(b"GIF87a\x01\x00"), Some("image/gif"));
+        assert_eq!(sniff_image_type(b"GIF89a\x01\x00"), Some("image/gif"));
+        assert_eq!(sniff_image_type(b"RIFF\x24\x00\x00\x00WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff_image_type(b"BM\x36\x00\x00\x00\x00\x00\x00\x00\x36\x00\x00\x00"), Some("image/bmp"));
+        assert_eq!(sniff_image_type(b"II*\x00\x08\x00\x00\x00"), Some("image/tiff"));
+        assert_eq!(sniff_image_type(b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00"), Some("image/avif"));
+
+        // Look-alikes that aren't images
+        assert_eq!(sniff_image_type(b"RIFF\x24\x00\x00\x00WAVEfmt "), None);
+        assert_eq!(sniff_image_type(b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00"), None);
+        assert_eq!(sniff_image_type(b"<!DOCTYPE html>"), None);
+        assert_eq!(sniff_image_type(b""), None);
+    }
+
+    #[test]
+    fn test_has_transparency() {
+        assert!(has_transparency(&encode_png(image::DynamicImage::new_rgba8(4, 4))));
+        assert!(has_transparency(&encode_png(image::DynamicImage::new_luma_a8(4, 4))));
+        assert!(!has_transparency(&encode_png(image::DynamicImage::new_rgb8(4, 4))));
+        assert!(has_transparency(&encode_gif(0)));
+        assert!(!has_transparency(&encode_gif(255)));
+
+        // Opaque color type, but a tRNS chunk before the pixel data
+        let mut with_trns = encode_png(image::DynamicImage::new_rgb8(4, 4));
+        let trns = [0, 0, 0, 6, b't', b'R', b'N', b'S', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        with_trns.splice(33..33, trns);
+        assert!(has_transparency(&with_trns));
+
+        assert!(!has_transparency(b"\xff\xd8\xff\xe0"));
+        assert!(!has_transparency(b"GIF89a"));
+    }
+
+    #[test]
+    fn test_config_validate() {
+        assert!(Config::default().validate().is_ok());
+        assert!(Config { min_compress_length: 6 * 1024 * 1024, ..Config::default() }.validate().is_err());
+        assert!(Config { max_original_size: 64 * 1024, ..Config::default() }.validate().is_err());
+        assert!(Config { benefit_margin: -1.0, ..Config::default() }.validate().is_err());
+        let mut config = Config::default();
+        config.type_thresholds.insert("image/png", TypeThresholds { min_size: Some(6 * 1024 * 1024), ..TypeThresholds::default() });
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_should_compress_type_thresholds() {
+        let mut config = Config::default();
+        config.type_thresholds.insert(
+            "image/jpeg",
+            TypeThresholds { min_size: Some(2 * 1024), max_size: Some(1024 * 1024), ..TypeThresholds::default() },
+        );
+
+        assert_eq!(should_compress("image/jpeg", 4 * 1024, false, OutputFormat::Jpeg, &config), CompressDecision::Compress);
+        assert_eq!(should_compress("Image/JPEG; q=1", 1024, false, OutputFormat::Jpeg, &config), CompressDecision::TooSmall);
+        assert_eq!(should_compress("image/jpeg", 2 * 1024 * 1024, false, OutputFormat::Jpeg, &config), CompressDecision::TooLarge);
+        // Other types keep the global thresholds
+        assert_eq!(should_compress("image/bmp", 4 * 1024, false, OutputFormat::Jpeg, &config), CompressDecision::TooSmall);
+        assert_eq!(should_compress("image/bmp", 50 * 1024, false, OutputFormat::Jpeg, &config), CompressDecision::Compress);
+        assert_eq!(should_compress("image/gif", 50 * 1024, false, OutputFormat::Jpeg, &config), CompressDecision::TransparentTooSmall);
+        assert_eq!(should_compress("image/gif", 50 * 1024, true, OutputFormat::Jpeg, &config), CompressDecision::Compress);
+    }
+
+    #[test]
+    fn test_parse_type_thresholds() {
+        let parsed = parse_type_thresholds("image/png:100k, IMAGE/JPEG:2048,image/gif::50k:1m").unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed["image/png"], TypeThresholds { min_size: Some(100 * 1024), ..TypeThresholds::default() });
+        assert_eq!(parsed["image/jpeg"], TypeThresholds { min_size: Some(2048), ..TypeThresholds::default() });
+        assert_eq!(
+            parsed["image/gif"],
+            TypeThresholds { min_size: None, min_transparent_size: Some(50 * 1024), max_size: Some(1024 * 1024) }
+        );
+        assert!(parse_type_thresholds("").unwrap().is_empty());
+
+        assert!(parse_type_thresholds("image/x-icon:10k").is_err());
+        assert!(parse_type_thresholds("image/png").is_err());
+        assert!(parse_type_thresholds("image/png:ten").is_err());
+        assert!(parse_type_thresholds("image/png:-1k").is_err());
+        assert!(parse_type_thresholds("image/png:1:2:3:4").is_err());
+        assert!(parse_type_thresholds("image/png:99999999999999999m").is_err());
+    }
+
+    #[test]
+    fn test_is_unlikely_to_benefit() {
+        let config = Config::default();
+        let cases = [
+            // (size, output pixels, output, quality, expected)
+            // 800x600 at q40: ~48 KB JPEG, ~24 KB AVIF expected
+            (12 * 1024, 800 * 600, OutputFormat::Jpeg, 40, true),
+            (60 * 1024, 800 * 600, OutputFormat::Jpeg, 40, true),
+            (70 * 1024, 800 * 600, OutputFormat::Jpeg, 40, false),
+            (25 * 1024, 800 * 600, OutputFormat::Avif, 40, true),
+            (40 * 1024, 800 * 600, OutputFormat::Avif, 40, false),
+            // Higher quality raises the estimate
+            (70 * 1024, 800 * 600, OutputFormat::Jpeg, 80, true),
+            // Downscaled output is much smaller than the source
+            (150 * 1024, 800 * 600, OutputFormat::Jpeg, 40, false),
+            // Large sources always get an encode attempt
+            (201 * 1024, 2000 * 2000, OutputFormat::Jpeg, 80, false),
+            // Lossless outputs have no estimate
+            (12 * 1024, 800 * 600, OutputFormat::Png, 40, false),
+            (12 * 1024, 800 * 600, OutputFormat::WebP, 40, false),
+        ];
+        for (size, pixels, output, quality, expected) in cases {
+            assert_eq!(
+                is_unlikely_to_benefit(size, pixels, output, quality, &config),
+                expected,
+                "{} bytes, {} px, {:?} q{}",
+                size, pixels, output, quality
+            );
+        }
+    }
+
+    fn encode_animated_gif(frames: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut data);
+            for i in 0..frames {
+                let img = image::RgbaImage::from_pixel(8, 8, image::Rgba([i as u8 * 60, 0, 0, 255]));
+                encoder.encode_frame(image::Frame::new(img)).unwrap();
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_is_animated_gif() {
+        assert!(is_animated_gif(&encode_animated_gif(3)));
+        assert!(!is_animated_gif(&encode_animated_gif(1)));
+        assert!(!is_animated_gif(&encode_png(image::DynamicImage::new_rgb8(4, 4))));
+
+        // Garbage after the frames doesn't matter
+        let mut trailing = encode_animated_gif(2);
+        trailing.pop();
+        trailing.extend_from_slice(b"\xde\xad\xbe\xef");
+        assert!(is_animated_gif(&trailing));
+
+        // Neither does a file cut off mid-frame
+        let truncated = encode_animated_gif(2);
+        assert!(!is_animated_gif(&truncated[..40]));
+    }
+
+    #[test]
+    fn test_should_compress_output_format_thresholds() {
+        let config = Config::default();
+
+        let cases = [
+            (OutputFormat::Avif, 4095, CompressDecision::TooSmall),
+            (OutputFormat::Avif, 4096, CompressDecision::Compress),
+            (OutputFormat::Jpeg, 4096, CompressDecision::TooSmall),
+            (OutputFormat::Jpeg, 10239, CompressDecision::TooSmall),
+            (OutputFormat::Jpeg, 10240, CompressDecision::Compress),
+            (OutputFormat::WebP, 10239, CompressDecision::TooSmall),
+            (OutputFormat::Png, 10240, CompressDecision::Compress),
+        ];
+        for (output, size, expected) in cases {
+            assert_eq!(should_compress("image/jpeg", size, false, output, &config), expected, "{:?} {}", output, size);
+        }
     }
 
     #[test]
     fn test_should_compress_transparent() {
         let config = Config::default();
-        assert!(should_compress("image/png", 50000, true, &config));
-        assert!(!should_compress("image/png", 5000, true, &config));
+        assert_eq!(should_compress("image/png", 50000, true, OutputFormat::Jpeg, &config), CompressDecision::Compress);
+        assert_eq!(should_compress("image/png", 50000, false, OutputFormat::Jpeg, &config), CompressDecision::TransparentTooSmall);
+        assert_eq!(should_compress("image/png", 5000, true, OutputFormat::Jpeg, &config), CompressDecision::TooSmall);
     }
 }
@@ -0,0 +1,186 @@
+// shutdown.rs - Serving until a shutdown signal, then draining requests
+
+use std::future::{Future, IntoFuture};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Request, State};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::serve::{Listener, ListenerExt};
+use axum::Router;
+use tokio::sync::watch;
+
+use crate::logger::Logger;
+
+/// What happened to the requests in progress when shutdown began
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Requests that finished before the drain timeout
+    pub drained: usize,
+    /// Requests still running at the drain timeout, dropped unfinished
+    pub aborted: usize,
+}
+
+/// Requests being handled right now
+#[derive(Debug, Default)]
+struct RequestTracker {
+    active: AtomicUsize,
+}
+
+/// Counts a request as active until dropped, finished or not
+struct ActiveRequest(Arc<RequestTracker>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn track_requests(State(tracker): State<Arc<RequestTracker>>, request: Request, next: Next) -> Response {
+    tracker.active.fetch_add(1, Ordering::SeqCst);
+    let _active = ActiveRequest(tracker);
+    next.run(request).await
+}
+
+/// Serve `app` until `signal` completes, then stop accepting connections
+/// and give the requests in progress `drain_timeout` to finish. Requests
+/// still running after that are dropped. `listener` is a plain
+/// `TcpListener`, or a `TlsListener` for HTTPS
+pub async fn serve_until<L: Listener<Addr = SocketAddr>>(
+    listener: L,
+    app: Router,
+    signal: impl Future<Output = ()>,
+    drain_timeout: Duration,
+    logger: &Logger,
+) -> std::io::Result<ShutdownSummary> {
+    let tracker = Arc::new(RequestTracker::default());
+    let app = app.layer(middleware::from_fn_with_state(tracker.clone(), track_requests));
+
+    let (stop, mut stopped) = watch::channel(false);
+    // Tapping gives any listener's connections their address as ConnectInfo
+    let listener = listener.tap_io(|_| {});
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            let _ = stopped.wait_for(|stopped| *stopped).await;
+        })
+        .into_future();
+    let mut server = tokio::spawn(server);
+
+    tokio::select! {
+        result = &mut server => {
+            // The server stopped on its own, e.g. the listener failed
+            result.expect("the server task is never aborted before shutdown")?;
+            return Ok(ShutdownSummary { drained: 0, aborted: 0 });
+        }
+        _ = signal => {}
+    }
+
+    let in_progress = tracker.active.load(Ordering::SeqCst);
+    logger.info("Shutting down, draining requests", &serde_json::json!({
+        "inProgress": in_progress,
+        "drainTimeoutMs": drain_timeout.as_millis() as u64,
+    }));
+    let _ = stop.send(true);
+
+    let aborted = match tokio::time::timeout(drain_timeout, &mut server).await {
+        Ok(result) => {
+            result.expect("the server task is never aborted before the drain timeout")?;
+            0
+        }
+        Err(_) => {
+            server.abort();
+            tracker.active.load(Ordering::SeqCst)
+        }
+    };
+    Ok(ShutdownSummary {
+        drained: in_progress.saturating_sub(aborted),
+        aborted,
+    })
+}
+
+/// Completes on SIGTERM, as container runtimes send on stop, or Ctrl-C
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    /// Serve a route that answers after `delay`, returning its address, the
+    /// trigger for shutdown and the summary it ends with
+    async fn serve_slow(
+        delay: Duration,
+        drain_timeout: Duration,
+    ) -> (SocketAddr, oneshot::Sender<()>, tokio::task::JoinHandle<ShutdownSummary>) {
+        let app = Router::new().route(
+            "/slow",
+            get(move || async move {
+                tokio::time::sleep(delay).await;
+                "done"
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (signal, signalled) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let signal = async move {
+                let _ = signalled.await;
+            };
+            serve_until(listener, app, signal, drain_timeout, &Logger::default()).await.unwrap()
+        });
+        (addr, signal, server)
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout_aborts_stuck_requests() {
+        let (addr, signal, server) = serve_slow(Duration::from_secs(30), Duration::from_millis(100)).await;
+        let request = tokio::spawn(reqwest::get(format!("http://{}/slow", addr)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started = std::time::Instant::now();
+        signal.send(()).unwrap();
+        let summary = server.await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(summary, ShutdownSummary { drained: 0, aborted: 1 });
+        request.abort();
+    }
+
+    #[tokio::test]
+    async fn test_idle_server_stops_at_once() {
+        let (addr, signal, server) = serve_slow(Duration::ZERO, Duration::from_secs(30)).await;
+        assert_eq!(reqwest::get(format!("http://{}/slow", addr)).await.unwrap().text().await.unwrap(), "done");
+
+        signal.send(()).unwrap();
+        let summary = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert_eq!(summary, ShutdownSummary { drained: 0, aborted: 0 });
+        // No longer accepting connections
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,239 @@
+// single_flight.rs - Coalescing of identical concurrent work
+
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures_util::future::{BoxFuture, FutureExt, Shared, WeakShared};
+
+type Flight<T> = Shared<BoxFuture<'static, Option<T>>>;
+
+/// A run in progress, and how many callers joined the one that started it
+struct Running<T> {
+    flight: WeakShared<BoxFuture<'static, Option<T>>>,
+    id: u64,
+    joined: usize,
+}
+
+type InFlight<T> = Arc<DashMap<String, Running<T>>>;
+
+/// Runs work once per key at a time, handing its result to every caller
+/// that asked for the same key meanwhile
+pub struct SingleFlight<T> {
+    in_flight: InFlight<T>,
+    next_id: AtomicU64,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        SingleFlight {
+            in_flight: Arc::new(DashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+}
+
+/// One run, as its work sees it
+pub struct Run<T> {
+    in_flight: InFlight<T>,
+    key: String,
+    id: u64,
+}
+
+impl<T> Run<T> {
+    /// Stop sharing the run with callers that come later, unless one
+    /// already joined it. Returns whether the result now goes to the
+    /// caller that started the run alone
+    pub fn go_alone(&self) -> bool {
+        self.in_flight
+            .remove_if(&self.key, |_, running| running.id == self.id && running.joined == 0)
+            .is_some()
+    }
+
+    /// Forget the run, if it is still the one for its key
+    fn forget(&self) {
+        self.in_flight.remove_if(&self.key, |_, running| running.id == self.id);
+    }
+}
+
+/// Forgets a run whose work was dropped unfinished
+struct Abandoned<T>(Run<T>);
+
+impl<T> Drop for Abandoned<T> {
+    fn drop(&mut self) {
+        self.0.forget();
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> SingleFlight<T> {
+    /// Run the work `work` builds for `key`, or wait for the run already in
+    /// progress. A run continues while any of its callers waits, so the one
+    /// that started it can go away, and is dropped once none is left.
+    /// Returns `None` if the work panicked, and whether the result came from
+    /// a run another caller started
+    pub async fn run<W, F>(&self, key: &str, work: W) -> (Option<T>, bool)
+    where
+        W: FnOnce(Run<T>) -> F,
+        F: Future<Output = T> + Send + 'static,
+    {
+        let (flight, coalesced) = self.join(key, work);
+        (flight.await, coalesced)
+    }
+
+    fn join<W, F>(&self, key: &str, work: W) -> (Flight<T>, bool)
+    where
+        W: FnOnce(Run<T>) -> F,
+        F: Future<Output = T> + Send + 'static,
+    {
+        let entry = match self.in_flight.entry(key.to_string()) {
+            Entry::Occupied(mut entry) => match entry.get().flight.upgrade() {
+                Some(flight) => {
+                    entry.get_mut().joined += 1;
+                    return (flight, true);
+                }
+                // Its callers are gone and it is being dropped
+                None => Entry::Occupied(entry),
+            },
+            entry => entry,
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let run = || Run {
+            in_flight: self.in_flight.clone(),
+            key: key.to_string(),
+            id,
+        };
+        let work = work(run());
+        let abandoned = Abandoned(run());
+        let flight = async move {
+            let output = AssertUnwindSafe(work).catch_unwind().await.ok();
+            abandoned.0.forget();
+            output
+        }
+        .boxed()
+        .shared();
+        let running = Running {
+            flight: flight.downgrade().expect("a new flight has not completed"),
+            id,
+            joined: 0,
+        };
+        match entry {
+            Entry::Occupied(mut entry) => {
+                entry.insert(running);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(running);
+            }
+        }
+        (flight, false)
+    }
+
+    /// Keys with work in progress
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_callers_share_one_run() {
+        let flights = SingleFlight::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let work = || {
+            let runs = runs.clone();
+            async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                42
+            }
+        };
+
+        let results = futures_util::future::join_all((0..4).map(|_| flights.run("key", |_| work()))).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|(value, _)| *value == Some(42)));
+        assert_eq!(results.iter().filter(|(_, coalesced)| *coalesced).count(), 3);
+        assert_eq!(flights.len(), 0);
+
+        // Finished runs aren't reused
+        assert_eq!(flights.run("key", |_| work()).await, (Some(42), false));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_outlives_the_caller_that_started_it() {
+        let flights = SingleFlight::default();
+        let work = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            "done"
+        };
+        let leader = tokio::time::timeout(Duration::from_millis(10), flights.run("key", |_| work));
+        let follower = flights.run("key", |_| async { "second run" });
+
+        let (leader, follower) = tokio::join!(leader, follower);
+        assert!(leader.is_err());
+        assert_eq!(follower, (Some("done"), true));
+    }
+
+    #[tokio::test]
+    async fn test_run_without_callers_is_dropped() {
+        let flights = SingleFlight::default();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let work = {
+            let finished = finished.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let abandoned = tokio::time::timeout(Duration::from_millis(10), flights.run("key", |_| work));
+        assert!(abandoned.await.is_err());
+        assert_eq!(flights.len(), 0);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(finished.load(Ordering::SeqCst), 0);
+        assert_eq!(flights.run("key", |_| async {}).await, (Some(()), false));
+    }
+
+    #[tokio::test]
+    async fn test_panicked_run_is_forgotten() {
+        let flights: SingleFlight<u32> = SingleFlight::default();
+        let (value, _) = flights.run("key", |_| async { panic!("boom") }).await;
+        assert_eq!(value, None);
+        assert_eq!(flights.len(), 0);
+        assert_eq!(flights.run("key", |_| async { 1 }).await, (Some(1), false));
+    }
+
+    #[tokio::test]
+    async fn test_run_gone_alone_is_not_shared() {
+        let flights = SingleFlight::default();
+        let work = |run: Run<bool>| async move {
+            let alone = run.go_alone();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            alone
+        };
+
+        // Later callers start their own run
+        let (first, _) = flights.join("key", work);
+        assert!(first.clone().now_or_never().is_none());
+        assert_eq!(flights.len(), 0);
+        let (second, coalesced) = flights.join("key", work);
+        assert!(!coalesced);
+        assert_eq!(tokio::join!(first, second), (Some(true), Some(true)));
+
+        // A caller that already joined keeps the run shared
+        let (first, _) = flights.join("key", work);
+        let (second, coalesced) = flights.join("key", work);
+        assert!(coalesced);
+        assert_eq!(tokio::join!(first, second), (Some(false), Some(false)));
+        assert_eq!(flights.len(), 0);
+    }
+}
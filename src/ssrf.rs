@@ -0,0 +1,223 @@
+// ssrf.rs - Keeps upstream fetches away from private networks
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use url::{Host, Url};
+
+use crate::dns::{DnsCache, DnsError};
+
+/// Why an upstream URL may not be fetched
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BlockedUpstream {
+    #[error("Unsupported URL scheme {0}")]
+    Scheme(String),
+    #[error("Upstream address {0} is not public")]
+    PrivateAddress(IpAddr),
+    #[error("Upstream host {0} did not resolve")]
+    Unresolved(String),
+}
+
+impl BlockedUpstream {
+    /// Machine-readable `code` for error responses
+    pub fn code(&self) -> &'static str {
+        match self {
+            BlockedUpstream::Scheme(_) => "unsupported-scheme",
+            BlockedUpstream::PrivateAddress(_) | BlockedUpstream::Unresolved(_) => "private-upstream",
+        }
+    }
+}
+
+/// Check everything about `url` that doesn't need DNS: the scheme and
+/// literal IP hosts. Hostnames are checked when `PublicResolver` resolves them
+pub fn check_upstream_url(url: &Url, allow_private: bool) -> Result<(), BlockedUpstream> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(BlockedUpstream::Scheme(url.scheme().to_string()));
+    }
+    if allow_private {
+        return Ok(());
+    }
+
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        _ => return Ok(()),
+    };
+    if is_public_ip(ip) {
+        Ok(())
+    } else {
+        Err(BlockedUpstream::PrivateAddress(ip))
+    }
+}
+
+/// Check if an address is reachable from the public internet, rather than
+/// loopback, RFC 1918, link-local, unique-local or otherwise internal
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_ipv4(mapped),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // "This network", 0.0.0.0/8
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && (b & 0xC0) == 64))
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local, fc00::/7
+        || (first & 0xFE00) == 0xFC00
+        // Link-local, fe80::/10
+        || (first & 0xFFC0) == 0xFE80)
+}
+
+/// DNS resolver that refuses hosts with any non-public address. The
+/// addresses it returns are the ones connected to, so a host can't pass
+/// the check and then rebind to a private address
+#[derive(Default)]
+pub struct PublicResolver {
+    cache: Arc<DnsCache>,
+    /// Operator configured hosts, like the egress proxy, allowed to be private
+    trusted_hosts: Vec<String>,
+}
+
+impl PublicResolver {
+    pub fn new(cache: Arc<DnsCache>, trusted_hosts: Vec<String>) -> Self {
+        PublicResolver { cache, trusted_hosts }
+    }
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let trusted = self.trusted_hosts.iter().any(|h| h.eq_ignore_ascii_case(name.as_str()));
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs = cache.resolve(&host).await.map_err(|source| DnsError {
+                host: host.clone(),
+                source,
+            })?;
+            if !trusted {
+                check_resolved(&host, &addrs)?;
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Reject a resolution that is empty or includes a non-public address
+pub fn check_resolved(host: &str, addrs: &[SocketAddr]) -> Result<(), BlockedUpstream> {
+    if addrs.is_empty() {
+        return Err(BlockedUpstream::Unresolved(host.to_string()));
+    }
+    match addrs.iter().map(SocketAddr::ip).find(|&ip| !is_public_ip(ip)) {
+        Some(ip) => Err(BlockedUpstream::PrivateAddress(ip)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(url: &str) -> Result<(), BlockedUpstream> {
+        check_upstream_url(&Url::parse(url).unwrap(), false)
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        let blocked = [
+            "127.0.0.1",
+            "127.8.9.10",
+            "10.0.0.1",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ];
+        for ip in blocked {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{} should be blocked", ip);
+        }
+
+        let public = [
+            "1.1.1.1",
+            "8.8.8.8",
+            "172.32.0.1",
+            "100.128.0.1",
+            "2606:4700:4700::1111",
+            "::ffff:8.8.8.8",
+        ];
+        for ip in public {
+            assert!(is_public_ip(ip.parse().unwrap()), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn test_check_upstream_url() {
+        assert_eq!(check("https://example.com/a.jpg"), Ok(()));
+        assert_eq!(check("http://8.8.8.8/a.jpg"), Ok(()));
+        assert_eq!(check("http://[2606:4700:4700::1111]/a.jpg"), Ok(()));
+
+        assert_eq!(check("file:///etc/passwd"), Err(BlockedUpstream::Scheme("file".to_string())));
+        assert_eq!(check("ftp://example.com/a.jpg"), Err(BlockedUpstream::Scheme("ftp".to_string())));
+        assert_eq!(
+            check("http://169.254.169.254/latest/meta-data"),
+            Err(BlockedUpstream::PrivateAddress("169.254.169.254".parse().unwrap()))
+        );
+        assert_eq!(check("http://[::1]:8080/admin"), Err(BlockedUpstream::PrivateAddress("::1".parse().unwrap())));
+        // Alternative spellings normalize to the same literal
+        assert!(check("http://2130706433/").is_err());
+        assert!(check("http://0x7f.1/").is_err());
+
+        // Names are left to the resolver; the escape hatch skips address checks
+        assert_eq!(check("http://localhost:8080/admin"), Ok(()));
+        assert_eq!(check_upstream_url(&Url::parse("http://127.0.0.1/").unwrap(), true), Ok(()));
+        assert!(check_upstream_url(&Url::parse("gopher://127.0.0.1/").unwrap(), true).is_err());
+    }
+
+    #[test]
+    fn test_check_resolved() {
+        let addr = |s: &str| SocketAddr::new(s.parse().unwrap(), 0);
+        assert_eq!(check_resolved("example.com", &[addr("93.184.216.34")]), Ok(()));
+        assert_eq!(
+            check_resolved("rebind.example", &[addr("93.184.216.34"), addr("10.0.0.5")]),
+            Err(BlockedUpstream::PrivateAddress("10.0.0.5".parse().unwrap()))
+        );
+        assert!(check_resolved("nothing.example", &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_public_resolver_rejects_localhost() {
+        let result = PublicResolver::default().resolve("localhost".parse().unwrap()).await;
+        let error = result.err().expect("localhost must not resolve");
+        assert!(error.downcast_ref::<BlockedUpstream>().is_some());
+
+        let trusted = PublicResolver::new(Arc::default(), vec!["LOCALHOST".to_string()]);
+        assert!(trusted.resolve("localhost".parse().unwrap()).await.is_ok());
+    }
+}
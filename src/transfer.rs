@@ -0,0 +1,108 @@
+// transfer.rs - Upstream bandwidth and fetch time accounting
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+/// Hosts tracked before the per-host totals start over
+const MAX_HOSTS: usize = 1024;
+
+/// Totals for one upstream host
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct HostTransfer {
+    pub host: String,
+    pub bytes: u64,
+    pub fetches: u64,
+}
+
+/// Bytes received from upstreams and time spent fetching, in total and
+/// per host
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    fetches: AtomicU64,
+    failed_fetches: AtomicU64,
+    bytes: AtomicU64,
+    fetch_ms: AtomicU64,
+    hosts: DashMap<String, HostTransfer>,
+}
+
+impl TransferStats {
+    /// Count `len` body bytes received from `host`
+    pub fn add_bytes(&self, host: &str, len: usize) {
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.host(host).bytes += len as u64;
+    }
+
+    /// Count a finished fetch from `host`, retries included
+    pub fn record_fetch(&self, host: &str, elapsed: Duration, ok: bool) {
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.failed_fetches.fetch_add(1, Ordering::Relaxed);
+        }
+        self.fetch_ms.fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+        self.host(host).fetches += 1;
+    }
+
+    fn host(&self, host: &str) -> dashmap::mapref::one::RefMut<'_, String, HostTransfer> {
+        let host = host.to_ascii_lowercase();
+        if self.hosts.len() >= MAX_HOSTS && !self.hosts.contains_key(&host) {
+            self.hosts.clear();
+        }
+        self.hosts.entry(host.clone()).or_insert_with(|| HostTransfer {
+            host,
+            ..Default::default()
+        })
+    }
+
+    pub fn fetches(&self) -> u64 {
+        self.fetches.load(Ordering::Relaxed)
+    }
+
+    pub fn failed_fetches(&self) -> u64 {
+        self.failed_fetches.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn fetch_ms(&self) -> u64 {
+        self.fetch_ms.load(Ordering::Relaxed)
+    }
+
+    /// The `n` hosts the most bytes came from
+    pub fn top_hosts(&self, n: usize) -> Vec<HostTransfer> {
+        let mut hosts: Vec<HostTransfer> = self.hosts.iter().map(|host| host.clone()).collect();
+        hosts.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.host.cmp(&b.host)));
+        hosts.truncate(n);
+        hosts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_totals_and_top_hosts() {
+        let stats = TransferStats::default();
+        stats.add_bytes("a.example.com", 100);
+        stats.add_bytes("B.example.com", 300);
+        stats.add_bytes("c.example.com", 200);
+        stats.add_bytes("a.example.com", 50);
+        stats.record_fetch("a.example.com", Duration::from_millis(40), true);
+        stats.record_fetch("b.example.com", Duration::from_millis(60), false);
+
+        assert_eq!(stats.bytes(), 650);
+        assert_eq!(stats.fetches(), 2);
+        assert_eq!(stats.failed_fetches(), 1);
+        assert_eq!(stats.fetch_ms(), 100);
+
+        let top = stats.top_hosts(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[0].host.as_str(), top[0].bytes, top[0].fetches), ("b.example.com", 300, 1));
+        assert_eq!((top[1].host.as_str(), top[1].bytes, top[1].fetches), ("c.example.com", 200, 0));
+    }
+}
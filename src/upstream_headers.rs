@@ -0,0 +1,141 @@
+// upstream_headers.rs - Extra headers sent to specific upstream hosts
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::http::{HeaderName, HeaderValue};
+
+use crate::proxy::HostList;
+
+/// Headers added to fetches from matching hosts, e.g. an API key some
+/// origin wants before it serves images
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamHeaders {
+    /// Least specific host first, so a subdomain's own values win
+    entries: Vec<(String, HostList, BTreeMap<String, String>)>,
+}
+
+impl UpstreamHeaders {
+    /// Parse `UPSTREAM_HEADERS`: comma separated `<host>: <JSON object>`
+    /// entries. A host matches itself and its subdomains
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        let mut rest = spec.trim();
+        while !rest.is_empty() {
+            let (host, json) = rest
+                .split_once(':')
+                .ok_or_else(|| format!("expected <host>: {{...}}, got \"{}\"", rest))?;
+            let host = host.trim().trim_start_matches("*.").trim_start_matches('.').to_ascii_lowercase();
+            if host.is_empty() || host.contains(['{', '}', ',', '*']) {
+                return Err(format!("invalid host \"{}\"", host));
+            }
+
+            let mut objects = serde_json::Deserializer::from_str(json).into_iter::<HashMap<String, String>>();
+            let headers = match objects.next() {
+                Some(Ok(headers)) => headers,
+                _ => return Err(format!("expected a JSON object of header values for {}", host)),
+            };
+            let mut checked = BTreeMap::new();
+            for (name, value) in headers {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("invalid header name \"{}\" for {}", name, host))?;
+                HeaderValue::from_str(&value).map_err(|_| format!("invalid value for {} for {}", name, host))?;
+                checked.insert(name.as_str().to_string(), value);
+            }
+            entries.push((host.clone(), HostList::parse(&host), checked));
+
+            rest = json[objects.byte_offset()..].trim_start();
+            rest = match rest.strip_prefix(',') {
+                Some(next) => next.trim_start(),
+                None if rest.is_empty() => rest,
+                None => return Err(format!("expected a comma after the headers for {}", host)),
+            };
+        }
+        entries.sort_by_key(|(host, _, _)| host.len());
+        Ok(UpstreamHeaders { entries })
+    }
+
+    /// Add the headers configured for `host` to `headers`, replacing any
+    /// already there
+    pub fn apply(&self, host: &str, headers: &mut HashMap<String, String>) {
+        for (_, _, values) in self.entries.iter().filter(|(_, hosts, _)| hosts.matches(host)) {
+            for (name, value) in values {
+                // Client headers are picked by their configured name's case
+                headers.retain(|existing, _| !existing.eq_ignore_ascii_case(name));
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+
+    /// Every configured header name, so their values can be kept out of logs
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().flat_map(|(_, _, values)| values.keys().map(String::as_str))
+    }
+
+    /// Hosts and the names of the headers they get, for logging
+    pub fn summary(&self) -> BTreeMap<&str, Vec<&str>> {
+        self.entries
+            .iter()
+            .map(|(host, _, values)| (host.as_str(), values.keys().map(String::as_str).collect()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let headers = UpstreamHeaders::parse(
+            r#"cdn.example.com: {"X-Api-Key": "a,b: {c}"}, img.cdn.example.com:{"referer":"https://example.com/"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            headers.summary(),
+            BTreeMap::from([
+                ("cdn.example.com", vec!["x-api-key"]),
+                ("img.cdn.example.com", vec!["referer"]),
+            ])
+        );
+        assert!(UpstreamHeaders::parse("").unwrap().summary().is_empty());
+
+        for invalid in [
+            "cdn.example.com",
+            "cdn.example.com: x-api-key=1",
+            r#"cdn.example.com: {"x-api-key": 1}"#,
+            r#"cdn.example.com: {"bad name": "1"}"#,
+            r#"cdn.example.com: {"x-api-key": "1"} other.com: {}"#,
+            r#": {"x-api-key": "1"}"#,
+        ] {
+            assert!(UpstreamHeaders::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_apply_matches_hosts_and_subdomains() {
+        let headers = UpstreamHeaders::parse(
+            r#"img.example.com: {"x-api-key": "inner"}, example.com: {"x-api-key": "outer", "x-requested-with": "app"}"#,
+        )
+        .unwrap();
+        let apply = |host: &str| {
+            let mut picked = HashMap::from([("X-Requested-With".to_string(), "client".to_string())]);
+            headers.apply(host, &mut picked);
+            picked
+        };
+
+        let picked = apply("example.com");
+        assert_eq!(picked.get("x-api-key").map(String::as_str), Some("outer"));
+        // Configured values replace the client's
+        assert_eq!(picked.get("x-requested-with").map(String::as_str), Some("app"));
+        assert!(!picked.contains_key("X-Requested-With"));
+
+        // The most specific host wins
+        let picked = apply("IMG.example.com");
+        assert_eq!(picked.get("x-api-key").map(String::as_str), Some("inner"));
+        assert_eq!(picked.get("x-requested-with").map(String::as_str), Some("app"));
+
+        for host in ["notexample.com", "example.org", "127.0.0.1"] {
+            assert_eq!(apply(host).len(), 1, "{}", host);
+        }
+    }
+}